@@ -1,45 +1,232 @@
-use std::{collections::HashSet, io::stdin};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    io::stdin,
+};
 
-fn part_one() {
-    let answer: i32 = stdin()
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Change {
+    Add(i64),
+    Mul(i64),
+    // `=N` resets the frequency
+    Set(i64),
+}
+
+impl Change {
+    // None on overflow
+    fn apply(self, frequency: i64) -> Option<i64> {
+        match self {
+            Change::Add(n) => frequency.checked_add(n),
+            Change::Mul(n) => frequency.checked_mul(n),
+            Change::Set(n) => Some(n),
+        }
+    }
+}
+
+// `+N`, `-N`, `*N` or `=N`; everything after a `#` is a comment, lines left empty are skipped
+fn parse_change(line: &str, line_number: usize) -> Option<Change> {
+    let line = line.split('#').next().unwrap().trim();
+    if line.is_empty() {
+        return None;
+    }
+
+    let (op, amount) = line.split_at(1);
+    let amount = amount
+        .trim()
+        .parse::<i64>()
+        .unwrap_or_else(|_| panic!("line {line_number}: bad amount in {line:?}"));
+    let change = match op {
+        "+" => Change::Add(amount),
+        "-" => Change::Add(-amount),
+        "*" => Change::Mul(amount),
+        "=" => Change::Set(amount),
+        _ => panic!("line {line_number}: unexpected op in {line:?}"),
+    };
+
+    Some(change)
+}
+
+// Applies the changes as their lines come in, optionally printing each resulting frequency
+struct Evaluator {
+    frequency: i64,
+    line_number: usize,
+    trace: bool,
+}
+
+impl Evaluator {
+    fn new(trace: bool) -> Self {
+        Evaluator {
+            frequency: 0,
+            line_number: 0,
+            trace,
+        }
+    }
+
+    fn feed(&mut self, line: &str) {
+        self.line_number += 1;
+        let Some(change) = parse_change(line, self.line_number) else {
+            return;
+        };
+        self.frequency = change
+            .apply(self.frequency)
+            .unwrap_or_else(|| panic!("line {}: frequency overflow", self.line_number));
+        if self.trace {
+            println!(
+                "{:>6}  {:<12} {}",
+                self.line_number,
+                line.split('#').next().unwrap().trim(),
+                self.frequency
+            );
+        }
+    }
+}
+
+fn part_one(trace: bool) {
+    let mut evaluator = Evaluator::new(trace);
+    for line in stdin().lines().map(|l| l.unwrap()) {
+        evaluator.feed(&line);
+    }
+
+    println!("{}", evaluator.frequency);
+}
+
+fn read_changes() -> Vec<Change> {
+    stdin()
         .lines()
         .map(|l| l.unwrap())
-        .map(|line| {
-            let (op, &amount) = (&line[..1], &line[1..].parse::<i32>().unwrap());
-            return match op {
-                "+" => amount,
-                "-" => -amount,
-                _ => todo!("unexpected op"),
-            };
-        })
-        .sum();
+        .enumerate()
+        .filter_map(|(i, line)| parse_change(&line, i + 1))
+        .collect()
+}
 
-    println!("{answer}");
+#[derive(Debug, Clone, Copy)]
+struct Repeat {
+    frequency: i64,
+    // Both 0-based, the change whose result is the first frequency reached twice
+    pass: usize,
+    index: usize,
 }
 
-fn part_two() {
-    let input_instrs = stdin().lines().map(|l| l.unwrap()).collect::<Vec<_>>();
-
-    let mut current = 0;
-    let mut seen = HashSet::new();
-
-    'outer: loop {
-        for line in &input_instrs {
-            let (op, &amount) = (&line[..1], &line[1..].parse::<i32>().unwrap());
-            current += match op {
-                "+" => amount,
-                "-" => -amount,
-                _ => todo!("unexpected op"),
+// After `time` changes the frequency is `prefix[time % n] + (time / n) * drift`, so a frequency
+// comes back either within the first pass or when an earlier prefix sum catches up with a later
+// one of the same residue modulo the drift; among those the closest one in the drift's
+// direction is the first to be reached. The initial 0 counts as reached.
+fn first_repeat_additive(changes: &[i64]) -> Option<Repeat> {
+    let n = changes.len();
+    if n == 0 {
+        return None;
+    }
+    let mut prefix = vec![0];
+    for c in changes {
+        prefix.push(prefix[prefix.len() - 1] + c);
+    }
+    let drift = prefix.pop().unwrap();
+
+    let mut first_seen = HashMap::new();
+    let mut best_time = None;
+    for (time, &frequency) in prefix.iter().enumerate() {
+        if first_seen.contains_key(&frequency) {
+            best_time = Some(time);
+            break;
+        }
+        first_seen.insert(frequency, time);
+    }
+    if drift == 0 && best_time.is_none() {
+        best_time = Some(n);
+    }
+
+    if drift != 0 {
+        let mut by_residue: HashMap<i64, Vec<(i64, usize)>> = HashMap::new();
+        for (&frequency, &time) in &first_seen {
+            by_residue
+                .entry(frequency.rem_euclid(drift))
+                .or_default()
+                .push((frequency, time));
+        }
+
+        for group in by_residue.values_mut() {
+            group.sort_by_key(|&(frequency, _)| frequency * drift.signum());
+            for pair in group.windows(2) {
+                let ((from, time), (to, _)) = (pair[0], pair[1]);
+                let passes = ((to - from) / drift) as usize;
+                let candidate = passes * n + time;
+                if best_time.is_none_or(|best| candidate < best) {
+                    best_time = Some(candidate);
+                }
+            }
+        }
+    }
+
+    best_time.map(|time| Repeat {
+        frequency: prefix[time % n] + (time / n) as i64 * drift,
+        pass: (time - 1) / n,
+        index: (time - 1) % n,
+    })
+}
+
+const MAX_SIMULATED_PASSES: usize = 1000;
+
+enum RepeatSearch {
+    Found(Repeat),
+    Never,
+    GaveUp(String),
+}
+
+// Additions only are solved by `first_repeat_additive`, anything else is simulated for a
+// bounded number of passes
+fn first_repeat(changes: &[Change]) -> RepeatSearch {
+    let amounts = changes
+        .iter()
+        .map(|c| match c {
+            Change::Add(n) => Some(*n),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>();
+    if let Some(amounts) = amounts {
+        return match first_repeat_additive(&amounts) {
+            Some(repeat) => RepeatSearch::Found(repeat),
+            None => RepeatSearch::Never,
+        };
+    }
+
+    let mut frequency = 0;
+    let mut seen = HashSet::from([frequency]);
+    for pass in 0..MAX_SIMULATED_PASSES {
+        for (index, change) in changes.iter().enumerate() {
+            let Some(next) = change.apply(frequency) else {
+                return RepeatSearch::GaveUp(format!("frequency overflow in pass {pass}"));
             };
-            if seen.contains(&current) {
-                println!("{current}");
-                break 'outer;
+            frequency = next;
+            if !seen.insert(frequency) {
+                return RepeatSearch::Found(Repeat {
+                    frequency,
+                    pass,
+                    index,
+                });
             }
-            seen.insert(current);
         }
     }
+
+    RepeatSearch::GaveUp(format!("no repeat within {MAX_SIMULATED_PASSES} passes"))
+}
+
+fn part_two() {
+    let changes = read_changes();
+
+    match first_repeat(&changes) {
+        RepeatSearch::Found(repeat) => println!(
+            "{} (pass {}, change {})",
+            repeat.frequency, repeat.pass, repeat.index
+        ),
+        RepeatSearch::Never => println!("no frequency is ever reached twice"),
+        RepeatSearch::GaveUp(reason) => println!("{reason}"),
+    }
 }
 
 fn main() {
-    part_two();
+    match env::args().nth(1).as_deref() {
+        Some("part_one") => part_one(false),
+        Some("trace") => part_one(true),
+        _ => part_two(),
+    }
 }
@@ -1,4 +1,9 @@
-use std::{collections::HashMap, hash::Hash, io::stdin};
+use std::{
+    collections::{HashMap, HashSet},
+    env,
+    hash::Hash,
+    io::stdin,
+};
 
 fn char_freqs(s: &str) -> HashMap<char, i32> {
     let mut result = HashMap::new();
@@ -18,55 +23,323 @@ fn has_letter_with_freq(map: &HashMap<char, i32>, n: i32) -> bool {
     return false;
 }
 
+// Product over `multiplicities` of how many IDs have a letter appearing exactly that many times;
+// the puzzle's checksum is the one for 2 and 3
+fn checksum(frequencies: &[HashMap<char, i32>], multiplicities: &[i32]) -> usize {
+    multiplicities
+        .iter()
+        .map(|&n| {
+            frequencies
+                .iter()
+                .filter(|&f| has_letter_with_freq(f, n))
+                .count()
+        })
+        .product()
+}
+
+fn letters_with_freq(map: &HashMap<char, i32>, n: i32) -> Vec<char> {
+    let mut result = map
+        .iter()
+        .filter(|(_, &v)| v == n)
+        .map(|(&c, _)| c)
+        .collect::<Vec<_>>();
+    result.sort();
+    result
+}
+
 fn part_one() {
     let box_ids = stdin().lines().map(|l| l.unwrap()).collect::<Vec<_>>();
     let frequencies = box_ids.iter().map(|l| char_freqs(l)).collect::<Vec<_>>();
 
-    let letter_twice = frequencies
-        .iter()
-        .filter(|&f| has_letter_with_freq(f, 2))
-        .count();
-    let letter_thrice = frequencies
-        .iter()
-        .filter(|&f| has_letter_with_freq(f, 3))
-        .count();
-
-    let result = letter_twice * letter_thrice;
+    let result = checksum(&frequencies, &[2, 3]);
     println!("{result}");
 }
 
-fn diff(s1: &str, s2: &str) -> Option<(usize, usize)> {
-    let mut cnt_diff = 0;
-    let mut last_diff_idx = 0;
-    for i in 0..s1.len() {
-        if s1.chars().nth(i) != s2.chars().nth(i) {
-            cnt_diff += 1;
-            last_diff_idx = i;
+// report <N>...: the letters appearing exactly N times in each ID, then the checksum over the Ns
+fn print_letter_report() {
+    let multiplicities = env::args()
+        .skip(2)
+        .map(|n| n.parse::<i32>().expect("usage: report <N>..."))
+        .collect::<Vec<_>>();
+    let box_ids = stdin().lines().map(|l| l.unwrap()).collect::<Vec<_>>();
+    let frequencies = box_ids.iter().map(|l| char_freqs(l)).collect::<Vec<_>>();
+
+    for (id, f) in box_ids.iter().zip(&frequencies) {
+        let columns = multiplicities
+            .iter()
+            .map(|&n| {
+                let letters = letters_with_freq(f, n).into_iter().collect::<String>();
+                if letters.is_empty() {
+                    format!("{n}: -")
+                } else {
+                    format!("{n}: {letters}")
+                }
+            })
+            .collect::<Vec<_>>();
+        println!("{id}  {}", columns.join("  "));
+    }
+    println!("checksum: {}", checksum(&frequencies, &multiplicities));
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Metric {
+    // Differing positions, only between IDs of the same length
+    Hamming,
+    // Insertions, deletions and substitutions
+    Edit,
+}
+
+#[derive(Debug, Clone)]
+struct Match {
+    first: usize,
+    second: usize,
+    distance: usize,
+    common: String,
+}
+
+// Every way of picking `k` positions out of `0..n`
+fn combinations(n: usize, k: usize) -> Vec<Vec<usize>> {
+    if k == 0 {
+        return vec![vec![]];
+    }
+    if k > n {
+        return vec![];
+    }
+
+    let mut result = combinations(n - 1, k);
+    for mut c in combinations(n - 1, k - 1) {
+        c.push(n - 1);
+        result.push(c);
+    }
+    result
+}
+
+// IDs within Hamming distance k share the key with all their differing positions blanked
+fn wildcard_keys(id: &[char], k: usize) -> Vec<String> {
+    combinations(id.len(), k.min(id.len()))
+        .into_iter()
+        .map(|positions| {
+            let mut key = id.to_vec();
+            for p in positions {
+                key[p] = '*';
+            }
+            key.into_iter().collect()
+        })
+        .collect()
+}
+
+// IDs within edit distance k share some string obtained by deleting at most k letters from each
+fn deletion_keys(id: &[char], k: usize) -> HashSet<String> {
+    let mut result = HashSet::from([id.iter().collect::<String>()]);
+    let mut frontier = vec![id.to_vec()];
+    for _ in 0..k {
+        let mut next = Vec::new();
+        for s in frontier {
+            for i in 0..s.len() {
+                let mut shorter = s.clone();
+                shorter.remove(i);
+                if result.insert(shorter.iter().collect()) {
+                    next.push(shorter);
+                }
+            }
         }
+        frontier = next;
     }
-    return if cnt_diff == 0 {
-        None
-    } else {
-        Some((cnt_diff, last_diff_idx))
-    };
+
+    result
 }
 
-fn part_two() {
-    let box_ids = stdin().lines().map(|l| l.unwrap()).collect::<Vec<_>>();
+fn edit_distance(a: &[char], b: &[char]) -> usize {
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for i in 1..=a.len() {
+        let mut diagonal = row[0];
+        row[0] = i;
+        for j in 1..=b.len() {
+            let substitution = diagonal + (a[i - 1] != b[j - 1]) as usize;
+            diagonal = row[j];
+            row[j] = substitution.min(row[j] + 1).min(row[j - 1] + 1);
+        }
+    }
+
+    row[b.len()]
+}
 
-    'outer: for id1 in &box_ids {
-        for id2 in &box_ids {
-            if let Some((1, pos)) = diff(id1, id2) {
-                let mut answer = id1[..pos].to_string();
-                answer.push_str(&id1[pos + 1..]);
+fn longest_common_subsequence(a: &[char], b: &[char]) -> String {
+    let mut lengths = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for i in (0..a.len()).rev() {
+        for j in (0..b.len()).rev() {
+            lengths[i][j] = if a[i] == b[j] {
+                lengths[i + 1][j + 1] + 1
+            } else {
+                lengths[i + 1][j].max(lengths[i][j + 1])
+            };
+        }
+    }
 
-                println!("{answer}");
-                break 'outer;
+    let (mut i, mut j, mut result) = (0, 0, String::new());
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            result.push(a[i]);
+            i += 1;
+            j += 1;
+        } else if lengths[i + 1][j] >= lengths[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    result
+}
+
+fn compare(a: &[char], b: &[char], metric: Metric) -> Option<(usize, String)> {
+    match metric {
+        Metric::Hamming if a.len() != b.len() => None,
+        Metric::Hamming => {
+            let common = a
+                .iter()
+                .zip(b)
+                .filter(|(x, y)| x == y)
+                .map(|(x, _)| *x)
+                .collect::<String>();
+            Some((a.len() - common.len(), common))
+        }
+        Metric::Edit => Some((edit_distance(a, b), longest_common_subsequence(a, b))),
+    }
+}
+
+// All pairs of IDs at distance 1..=k, found by bucketing the IDs on keys that near IDs have in
+// common and only comparing within buckets
+fn near_duplicates(ids: &[String], k: usize, metric: Metric) -> Vec<Match> {
+    let chars = ids
+        .iter()
+        .map(|id| id.chars().collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    let mut buckets: HashMap<String, Vec<usize>> = HashMap::new();
+    for (i, id) in chars.iter().enumerate() {
+        let keys = match metric {
+            Metric::Hamming => wildcard_keys(id, k),
+            Metric::Edit => deletion_keys(id, k).into_iter().collect(),
+        };
+        for key in keys {
+            buckets.entry(key).or_default().push(i);
+        }
+    }
+
+    let mut candidates = HashSet::new();
+    for bucket in buckets.values() {
+        for (n, &i) in bucket.iter().enumerate() {
+            for &j in &bucket[n + 1..] {
+                candidates.insert((i.min(j), i.max(j)));
             }
         }
     }
+
+    let mut result = candidates
+        .into_iter()
+        .filter_map(|(first, second)| {
+            let (distance, common) = compare(&chars[first], &chars[second], metric)?;
+            (1..=k).contains(&distance).then_some(Match {
+                first,
+                second,
+                distance,
+                common,
+            })
+        })
+        .collect::<Vec<_>>();
+    result.sort_by_key(|m| (m.first, m.second));
+
+    result
+}
+
+fn find_root(parents: &mut [usize], i: usize) -> usize {
+    if parents[i] != i {
+        parents[i] = find_root(parents, parents[i]);
+    }
+    parents[i]
+}
+
+// Groups of IDs connected through near-duplicate pairs, IDs without any left out
+fn clusters(ids: &[String], k: usize, metric: Metric) -> Vec<Vec<usize>> {
+    let mut parents = (0..ids.len()).collect::<Vec<_>>();
+    for m in near_duplicates(ids, k, metric) {
+        let (a, b) = (
+            find_root(&mut parents, m.first),
+            find_root(&mut parents, m.second),
+        );
+        parents[a.max(b)] = a.min(b);
+    }
+
+    let mut groups: HashMap<usize, Vec<usize>> = HashMap::new();
+    for i in 0..ids.len() {
+        groups
+            .entry(find_root(&mut parents, i))
+            .or_default()
+            .push(i);
+    }
+    let mut result = groups
+        .into_values()
+        .filter(|g| g.len() > 1)
+        .collect::<Vec<_>>();
+    result.sort_by_key(|g| (usize::MAX - g.len(), g[0]));
+
+    result
+}
+
+fn part_two() {
+    let box_ids = stdin().lines().map(|l| l.unwrap()).collect::<Vec<_>>();
+
+    if let Some(m) = near_duplicates(&box_ids, 1, Metric::Hamming).first() {
+        println!("{}", m.common);
+    }
+}
+
+// near <k> [edit]: every pair of IDs within distance k with the letters they have in common
+fn print_near_duplicates() {
+    let k = env::args()
+        .nth(2)
+        .and_then(|k| k.parse::<usize>().ok())
+        .expect("usage: near <k> [edit]");
+    let metric = match env::args().nth(3).as_deref() {
+        Some("edit") => Metric::Edit,
+        _ => Metric::Hamming,
+    };
+    let box_ids = stdin().lines().map(|l| l.unwrap()).collect::<Vec<_>>();
+
+    for m in near_duplicates(&box_ids, k, metric) {
+        println!(
+            "{} {} {} {}",
+            box_ids[m.first], box_ids[m.second], m.distance, m.common
+        );
+    }
+}
+
+// clusters <k> [edit]: IDs linked by chains of pairs within distance k, one cluster per line
+fn print_clusters() {
+    let k = env::args()
+        .nth(2)
+        .and_then(|k| k.parse::<usize>().ok())
+        .expect("usage: clusters <k> [edit]");
+    let metric = match env::args().nth(3).as_deref() {
+        Some("edit") => Metric::Edit,
+        _ => Metric::Hamming,
+    };
+    let box_ids = stdin().lines().map(|l| l.unwrap()).collect::<Vec<_>>();
+
+    for cluster in clusters(&box_ids, k, metric) {
+        let ids = cluster
+            .iter()
+            .map(|&i| box_ids[i].as_str())
+            .collect::<Vec<_>>();
+        println!("{} {}", ids.len(), ids.join(" "));
+    }
 }
 
 fn main() {
-    part_two();
+    match env::args().nth(1).as_deref() {
+        Some("near") => print_near_duplicates(),
+        Some("clusters") => print_clusters(),
+        Some("report") => print_letter_report(),
+        Some("part_one") => part_one(),
+        _ => part_two(),
+    }
 }
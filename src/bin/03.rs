@@ -1,6 +1,10 @@
 use scanf::sscanf;
 use std::cmp::{max, min};
-use std::{collections::HashSet, io::stdin};
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    io::stdin,
+};
 
 #[derive(Clone, Copy, Hash, PartialEq, Eq)]
 struct Point {
@@ -10,6 +14,8 @@ struct Point {
 
 #[derive(Clone, Copy)]
 struct Rect {
+    // The claim's `#n`, 0 for rectangles that aren't claims
+    id: i32,
     up_left: Point,
     width: i32,
     height: i32,
@@ -35,28 +41,24 @@ fn intersection(a: Rect, b: Rect) -> Rect {
     };
 
     return Rect {
+        id: 0,
         up_left: min_result,
         width: max_result.x - min_result.x,
         height: max_result.y - min_result.y,
     };
 }
 
-fn points(a: Rect) -> Vec<Point> {
-    let mut result = Vec::new();
-    for y in a.up_left.y..a.up_left.y + a.height {
-        for x in a.up_left.x..a.up_left.x + a.width {
-            result.push(Point { x: x, y: y });
-        }
-    }
-    return result;
+fn parse_rects() -> Vec<Rect> {
+    parse_rects_from(stdin().lines().map(|l| l.unwrap()))
 }
 
-fn parse_rects() -> Vec<Rect> {
+fn parse_rects_from(lines: impl Iterator<Item = String>) -> Vec<Rect> {
     let mut rects = Vec::new();
-    for line in stdin().lines().map(|l| l.unwrap()) {
+    for line in lines {
         let (mut n, mut x, mut y, mut w, mut h) = (0, 0, 0, 0, 0);
         if sscanf!(&line, "#{} @ {},{}: {}x{}", n, x, y, w, h).is_ok() {
             rects.push(Rect {
+                id: n,
                 up_left: Point { x: x, y: y },
                 width: w,
                 height: h,
@@ -67,51 +69,457 @@ fn parse_rects() -> Vec<Rect> {
     return rects;
 }
 
-fn find_overlapping_points_count(rects: &Vec<Rect>) -> usize {
-    let mut overlapping = HashSet::new();
-    for i in 0..rects.len() {
-        for j in i + 1..rects.len() {
-            let (rect_i, rect_j) = (rects[i], rects[j]);
-            for p in points(intersection(rect_i, rect_j)) {
-                overlapping.insert(p);
-            }
+// How many claims cover each cell of the smallest rectangle containing all of them
+struct Coverage {
+    counts: Vec<u32>,
+}
+
+// A 2D difference array: each claim adds 1 at its top left corner and -1 just past its other
+// corners, so prefix sums over rows then columns give every cell's count in a single pass
+fn coverage(rects: &[Rect]) -> Coverage {
+    let nonempty = rects.iter().filter(|r| area(**r) > 0);
+    let origin = Point {
+        x: nonempty.clone().map(|r| r.up_left.x).min().unwrap_or(0),
+        y: nonempty.clone().map(|r| r.up_left.y).min().unwrap_or(0),
+    };
+    let width = nonempty
+        .clone()
+        .map(|r| (r.up_left.x + r.width - origin.x) as usize)
+        .max()
+        .unwrap_or(0);
+    let height = nonempty
+        .clone()
+        .map(|r| (r.up_left.y + r.height - origin.y) as usize)
+        .max()
+        .unwrap_or(0);
+
+    let mut diff = vec![0i32; (width + 1) * (height + 1)];
+    for r in nonempty {
+        let (x, y) = (
+            (r.up_left.x - origin.x) as usize,
+            (r.up_left.y - origin.y) as usize,
+        );
+        let (x_end, y_end) = (x + r.width as usize, y + r.height as usize);
+        diff[y * (width + 1) + x] += 1;
+        diff[y * (width + 1) + x_end] -= 1;
+        diff[y_end * (width + 1) + x] -= 1;
+        diff[y_end * (width + 1) + x_end] += 1;
+    }
+    for y in 0..=height {
+        for x in 1..=width {
+            diff[y * (width + 1) + x] += diff[y * (width + 1) + x - 1];
+        }
+    }
+    for y in 1..=height {
+        for x in 0..=width {
+            diff[y * (width + 1) + x] += diff[(y - 1) * (width + 1) + x];
         }
     }
 
-    return overlapping.len();
+    let mut counts = Vec::with_capacity(width * height);
+    for y in 0..height {
+        counts.extend(
+            diff[y * (width + 1)..y * (width + 1) + width]
+                .iter()
+                .map(|&c| c as u32),
+        );
+    }
+
+    Coverage { counts }
+}
+
+impl Coverage {
+    // Square inches covered by at least `k` claims
+    fn area_covered_by_at_least(&self, k: u32) -> usize {
+        self.counts.iter().filter(|&&c| c >= k).count()
+    }
 }
 
 fn part_one() {
     let rects = parse_rects();
-    let result = find_overlapping_points_count(&rects);
+    let result = coverage(&rects).area_covered_by_at_least(2);
     println!("{}", result);
 }
 
+// covered <k>...: the area covered by at least each k
+fn print_covered_areas() {
+    let rects = parse_rects();
+    let coverage = coverage(&rects);
+    for k in env::args().skip(2) {
+        let k = k.parse::<u32>().expect("usage: covered <k>...");
+        println!("{k}: {}", coverage.area_covered_by_at_least(k));
+    }
+}
+
 fn overlaps(a: Rect, b: Rect) -> bool {
     let r = intersection(a, b);
     return r.width > 0 && r.height > 0;
 }
 
+fn area(a: Rect) -> i32 {
+    a.width.max(0) * a.height.max(0)
+}
+
+// Every pair of overlapping claims as (id, other id, overlapping square inches), ordered by ids
+fn overlap_graph(rects: &[Rect]) -> Vec<(i32, i32, i32)> {
+    let mut result = Vec::new();
+    for i in 0..rects.len() {
+        for j in i + 1..rects.len() {
+            let (a, b) = (rects[i], rects[j]);
+            if overlaps(a, b) {
+                let overlap = area(intersection(a, b));
+                result.push((a.id.min(b.id), a.id.max(b.id), overlap));
+            }
+        }
+    }
+    result.sort();
+
+    result
+}
+
 fn part_two() {
     let rects = parse_rects();
-    let mut result = 0;
-    for i in 0..rects.len() {
-        let rect = rects[i];
-        let mut ok = true;
-        for j in 0..rects.len() {
-            if i != j && overlaps(rect, rects[j]) {
-                ok = false;
-                break;
+    let overlapping = overlap_graph(&rects)
+        .into_iter()
+        .flat_map(|(a, b, _)| [a, b])
+        .collect::<HashSet<_>>();
+
+    for rect in &rects {
+        if !overlapping.contains(&rect.id) {
+            println!("{}", rect.id);
+        }
+    }
+}
+
+// graph [csv|dot]: the overlap graph, claims without overlaps are nodes without edges in DOT
+fn print_overlap_graph() {
+    let rects = parse_rects();
+    let graph = overlap_graph(&rects);
+
+    match env::args().nth(2).as_deref() {
+        Some("dot") => {
+            println!("graph overlaps {{");
+            for rect in &rects {
+                println!("    {};", rect.id);
+            }
+            for (a, b, overlap) in graph {
+                println!("    {a} -- {b} [label={overlap}];");
+            }
+            println!("}}");
+        }
+        _ => {
+            println!("claim,other_claim,overlap");
+            for (a, b, overlap) in graph {
+                println!("{a},{b},{overlap}");
+            }
+        }
+    }
+}
+
+// Claims bucketed by the grid cells of side CELL_SIZE they touch, so that a query only looks at
+// the claims sharing a cell with it
+struct GridIndex {
+    rects: Vec<Rect>,
+    buckets: HashMap<(i32, i32), Vec<usize>>,
+}
+
+const CELL_SIZE: i32 = 32;
+
+fn cell_range(from: i32, len: i32) -> std::ops::RangeInclusive<i32> {
+    from.div_euclid(CELL_SIZE)..=(from + len.max(1) - 1).div_euclid(CELL_SIZE)
+}
+
+impl GridIndex {
+    fn new(rects: Vec<Rect>) -> Self {
+        let mut buckets: HashMap<(i32, i32), Vec<usize>> = HashMap::new();
+        for (i, r) in rects.iter().enumerate().filter(|(_, r)| area(**r) > 0) {
+            for cy in cell_range(r.up_left.y, r.height) {
+                for cx in cell_range(r.up_left.x, r.width) {
+                    buckets.entry((cx, cy)).or_default().push(i);
+                }
+            }
+        }
+
+        GridIndex { rects, buckets }
+    }
+
+    // Claims overlapping `region`
+    fn search(&self, region: Rect) -> Vec<Rect> {
+        let mut found = HashSet::new();
+        for cy in cell_range(region.up_left.y, region.height) {
+            for cx in cell_range(region.up_left.x, region.width) {
+                for &i in self.buckets.get(&(cx, cy)).into_iter().flatten() {
+                    if overlaps(self.rects[i], region) {
+                        found.insert(i);
+                    }
+                }
+            }
+        }
+
+        let mut result = found.into_iter().map(|i| self.rects[i]).collect::<Vec<_>>();
+        result.sort_by_key(|r| r.id);
+        result
+    }
+
+    fn covering(&self, p: Point) -> Vec<i32> {
+        let cell = Rect {
+            id: 0,
+            up_left: p,
+            width: 1,
+            height: 1,
+        };
+        self.search(cell).into_iter().map(|r| r.id).collect()
+    }
+
+    // Cells of `region` covered by at least two claims
+    fn contested_in(&self, region: Rect) -> usize {
+        let clipped = self
+            .search(region)
+            .into_iter()
+            .map(|r| intersection(r, region))
+            .collect::<Vec<_>>();
+        coverage(&clipped).area_covered_by_at_least(2)
+    }
+}
+
+const QUERY_HELP: &str = "at <x> <y>: claims covering the cell, \
+region <x> <y> <width> <height>: contested cells and the claims involved, q: quit";
+
+// The claims come from the file given after `query` since stdin is used for queries
+fn query() {
+    let path = env::args().nth(2).expect("usage: query <claims file>");
+    let claims = fs::read_to_string(path).expect("should be able to read the claims");
+    let index = GridIndex::new(parse_rects_from(claims.lines().map(|l| l.to_string())));
+
+    println!("{QUERY_HELP}");
+    for line in stdin().lines().map(|l| l.unwrap()) {
+        let args = line
+            .split_whitespace()
+            .skip(1)
+            .map(|v| v.parse::<i32>())
+            .collect::<Result<Vec<_>, _>>();
+        match (line.split_whitespace().next(), args.as_deref()) {
+            (Some("at"), Ok(&[x, y])) => {
+                println!("{:?}", index.covering(Point { x, y }));
+            }
+            (Some("region"), Ok(&[x, y, width, height])) => {
+                let region = Rect {
+                    id: 0,
+                    up_left: Point { x, y },
+                    width,
+                    height,
+                };
+                let ids = index
+                    .search(region)
+                    .iter()
+                    .map(|r| r.id)
+                    .collect::<Vec<_>>();
+                println!("{} contested, claims {ids:?}", index.contested_in(region));
+            }
+            (Some("q"), _) => break,
+            _ => println!("{QUERY_HELP}"),
+        }
+    }
+}
+
+// Which cells of the fabric are taken, along with how many free cells start at each cell and
+// continue to the right, so that checking a rectangle costs one lookup per row
+struct Fabric {
+    width: usize,
+    height: usize,
+    occupied: Vec<Vec<bool>>,
+    free_run: Vec<Vec<usize>>,
+}
+
+impl Fabric {
+    fn new(width: usize, height: usize) -> Self {
+        Fabric {
+            width,
+            height,
+            occupied: vec![vec![false; width]; height],
+            free_run: vec![(1..=width).rev().collect(); height],
+        }
+    }
+
+    fn fits(&self, x: i32, y: i32, r: Rect) -> bool {
+        let (w, h) = (r.width as usize, r.height as usize);
+        x >= 0
+            && y >= 0
+            && x as usize + w <= self.width
+            && y as usize + h <= self.height
+            && (y as usize..y as usize + h).all(|row| self.free_run[row][x as usize] >= w)
+    }
+
+    fn occupy(&mut self, r: Rect) {
+        let (x, y) = (r.up_left.x as usize, r.up_left.y as usize);
+        for row in y..y + r.height as usize {
+            for cell in &mut self.occupied[row][x..x + r.width as usize] {
+                *cell = true;
+            }
+            let mut run = 0;
+            for col in (0..self.width).rev() {
+                run = if self.occupied[row][col] { 0 } else { run + 1 };
+                self.free_run[row][col] = run;
             }
         }
-        if ok {
-            result = i + 1;
-            break;
+    }
+
+    // The free position closest to where `r` is now, by Manhattan distance; `r` may start outside
+    // the bounds, the search goes from the nearest position within them to the farthest
+    fn nearest_free(&self, r: Rect) -> Option<Point> {
+        let (x0, y0) = (r.up_left.x, r.up_left.y);
+        let (max_x, max_y) = (self.width as i32 - r.width, self.height as i32 - r.height);
+        if max_x < 0 || max_y < 0 {
+            return None;
+        }
+        let nearest = (x0 - x0.clamp(0, max_x)).abs() + (y0 - y0.clamp(0, max_y)).abs();
+        let farthest = x0.abs().max((x0 - max_x).abs()) + y0.abs().max((y0 - max_y).abs());
+        for d in nearest..=farthest {
+            for dy in -d..=d {
+                let dx = d - dy.abs();
+                for x in [x0 - dx, x0 + dx] {
+                    if self.fits(x, y0 + dy, r) {
+                        return Some(Point { x, y: y0 + dy });
+                    }
+                }
+            }
         }
+
+        None
+    }
+}
+
+// Claims in the same order with positions that don't overlap, where possible within the
+// bounds: claims overlapping nothing stay, the others are placed largest first at the nearest
+// free spot. This is greedy, a claim that finds no spot keeps its position (moved into the
+// bounds) and still overlaps; with more claimed area than the fabric has, none of them fit.
+fn relocate(rects: &[Rect], width: usize, height: usize) -> Vec<Rect> {
+    let overlapping = overlap_graph(rects)
+        .into_iter()
+        .flat_map(|(a, b, _)| [a, b])
+        .collect::<HashSet<_>>();
+    let mut order = (0..rects.len()).collect::<Vec<_>>();
+    order.sort_by_key(|&i| (overlapping.contains(&rects[i].id), -area(rects[i]), i));
+
+    let mut fabric = Fabric::new(width, height);
+    let mut result = rects.to_vec();
+    for i in order {
+        let r = rects[i];
+        if area(r) == 0 {
+            continue;
+        }
+        let position = fabric.nearest_free(r).unwrap_or(Point {
+            x: r.up_left.x.clamp(0, (width as i32 - r.width).max(0)),
+            y: r.up_left.y.clamp(0, (height as i32 - r.height).max(0)),
+        });
+        result[i].up_left = position;
+        if fabric.fits(position.x, position.y, r) {
+            fabric.occupy(result[i]);
+        }
+    }
+
+    result
+}
+
+const FABRIC_SIZE: usize = 1000;
+
+// relocate [width height]: the new layout in the input format, with each claim's displacement
+// and the overlap left on stderr
+fn print_relocated() {
+    let bounds = env::args()
+        .skip(2)
+        .map(|v| v.parse::<usize>().expect("usage: relocate [width height]"))
+        .collect::<Vec<_>>();
+    let (width, height) = match bounds[..] {
+        [width, height] => (width, height),
+        _ => (FABRIC_SIZE, FABRIC_SIZE),
+    };
+    let rects = parse_rects();
+    let relocated = relocate(&rects, width, height);
+
+    let mut total = 0;
+    for (before, after) in rects.iter().zip(&relocated) {
+        let (dx, dy) = (
+            after.up_left.x - before.up_left.x,
+            after.up_left.y - before.up_left.y,
+        );
+        total += dx.abs() + dy.abs();
+        println!(
+            "#{} @ {},{}: {}x{}",
+            after.id, after.up_left.x, after.up_left.y, after.width, after.height
+        );
+        if dx != 0 || dy != 0 {
+            eprintln!("#{} moved by {dx},{dy}", after.id);
+        }
+    }
+
+    let overlap = coverage(&relocated).area_covered_by_at_least(2);
+    eprintln!("total displacement: {total}");
+    if overlap == 0 {
+        eprintln!("all claims fit without overlapping");
+    } else {
+        eprintln!("{overlap} square inches still overlap");
+    }
+    let claimed = rects.iter().map(|r| area(*r) as usize).sum::<usize>();
+    if claimed > width * height {
+        eprintln!("the claims need {claimed} square inches, more than the fabric has");
     }
-    println!("{result}");
 }
 
 fn main() {
-    part_two();
+    match env::args().nth(1).as_deref() {
+        Some("graph") => print_overlap_graph(),
+        Some("part_one") => part_one(),
+        Some("covered") => print_covered_areas(),
+        Some("query") => query(),
+        Some("relocate") => print_relocated(),
+        _ => part_two(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn claim(id: i32, x: i32, y: i32, width: i32, height: i32) -> Rect {
+        Rect {
+            id,
+            up_left: Point { x, y },
+            width,
+            height,
+        }
+    }
+
+    fn inside(r: Rect, width: i32, height: i32) -> bool {
+        r.up_left.x >= 0
+            && r.up_left.y >= 0
+            && r.up_left.x + r.width <= width
+            && r.up_left.y + r.height <= height
+    }
+
+    #[test]
+    fn relocates_claims_starting_outside_the_bounds() {
+        let rects = [
+            claim(1, 900, 900, 3, 3),
+            claim(2, 901, 901, 3, 3),
+            claim(3, -50, 40, 10, 10),
+            claim(4, -45, 45, 10, 10),
+        ];
+        let relocated = relocate(&rects, 100, 100);
+
+        assert!(relocated.iter().all(|&r| inside(r, 100, 100)));
+        assert_eq!(coverage(&relocated).area_covered_by_at_least(2), 0);
+        // The nearest spots to where they were
+        assert_eq!((relocated[0].up_left.x, relocated[0].up_left.y), (97, 97));
+        assert_eq!((relocated[2].up_left.x, relocated[2].up_left.y), (0, 40));
+    }
+
+    #[test]
+    fn keeps_claims_that_cannot_fit() {
+        let rects = [claim(1, 0, 0, 20, 5), claim(2, 3, 3, 5, 5)];
+        let relocated = relocate(&rects, 10, 10);
+
+        assert_eq!((relocated[0].up_left.x, relocated[0].up_left.y), (0, 0));
+        assert!(inside(relocated[1], 10, 10));
+    }
 }
@@ -1,7 +1,10 @@
 use scanf::sscanf;
 use std::{
-    collections::{HashMap, HashSet},
+    cmp::Reverse,
+    collections::{BTreeMap, HashMap, HashSet},
+    env, fmt,
     io::stdin,
+    process,
     thread::current,
 };
 
@@ -21,65 +24,243 @@ impl Interval {
     }
 }
 
-fn find_guard_most_asleep(guard_sleep_intervals: &HashMap<i32, Vec<Interval>>) -> (i32, i32) {
-    let guard = *guard_sleep_intervals
-        .keys()
-        .max_by_key(|k| {
-            guard_sleep_intervals[k]
-                .iter()
-                .map(|&interval| len(interval))
-                .sum::<i32>()
-        })
-        .unwrap();
+// None if nobody ever slept; ties go to the lowest guard id and the earliest minute
+fn find_guard_most_asleep(
+    guard_sleep_intervals: &HashMap<i32, Vec<Interval>>,
+) -> Option<(i32, i32)> {
+    let guard = *guard_sleep_intervals.keys().max_by_key(|&k| {
+        let asleep = guard_sleep_intervals[k]
+            .iter()
+            .map(|&interval| len(interval))
+            .sum::<i32>();
+        (asleep, Reverse(*k))
+    })?;
 
     let minute = (0..60)
-        .max_by_key(|minute| {
-            guard_sleep_intervals[&guard]
+        .max_by_key(|&minute| {
+            let count = guard_sleep_intervals[&guard]
                 .iter()
-                .filter(|&interval| interval.contains(*minute))
-                .count()
+                .filter(|&interval| interval.contains(minute))
+                .count();
+            (count, Reverse(minute))
         })
         .unwrap();
 
-    return (guard, minute);
+    Some((guard, minute))
 }
 
-fn read_guards_data() -> HashMap<i32, Vec<Interval>> {
-    let mut current_guard = 0;
-    let (mut start, mut end) = (0, 0);
-    let mut guard_sleep_intervals = HashMap::new();
-    let mut entries = stdin().lines().map(|l| l.unwrap()).collect::<Vec<_>>();
-    entries.sort();
-
-    for line in entries {
-        let (mut date, mut time) = (String::new(), String::new());
-        let mut n = 0;
-        let mut minute = 0;
-
-        if sscanf!(&line, "[{} {}] Guard #{} begins shift", date, time, n).is_ok() {
-            current_guard = n;
-        } else if sscanf!(&line, "[{} 00:{}] falls asleep", date, minute).is_ok() {
-            start = minute;
-        } else if sscanf!(&line, "[{} 00:{}] wakes up", date, minute).is_ok() {
-            end = minute;
-            guard_sleep_intervals
-                .entry(current_guard)
-                .or_insert(Vec::new())
-                .push(Interval {
-                    min: start,
-                    max: end,
+// Minutes since 0000-03-01, so that timestamps can be compared and subtracted
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+struct Timestamp(i64);
+
+const MINUTES_PER_DAY: i64 = 24 * 60;
+
+impl Timestamp {
+    fn new(year: i64, month: i64, day: i64, hour: i64, minute: i64) -> Self {
+        // Days from the civil calendar, with years starting in March so leap days come last
+        let (y, m) = if month <= 2 {
+            (year - 1, month + 9)
+        } else {
+            (year, month - 3)
+        };
+        let days = 365 * y + y.div_euclid(4) - y.div_euclid(100)
+            + y.div_euclid(400)
+            + (153 * m + 2) / 5
+            + day
+            - 1;
+        Timestamp(days * MINUTES_PER_DAY + hour * 60 + minute)
+    }
+
+    // Shifts starting in the hour before midnight belong to the next night
+    fn night(self) -> i64 {
+        (self.0 + 60).div_euclid(MINUTES_PER_DAY)
+    }
+
+    // Minutes since the start of the midnight hour of `night`
+    fn minute_of(self, night: i64) -> i64 {
+        self.0 - night * MINUTES_PER_DAY
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Entry {
+    BeginsShift(i32),
+    FallsAsleep,
+    WakesUp,
+}
+
+#[derive(Debug)]
+enum LogError {
+    Unknown { line: usize, text: String },
+    SleepWithoutGuard { line: usize },
+    WakeWithoutSleep { line: usize },
+    SleepWhileAsleep { line: usize, asleep_since: usize },
+    SleepWithoutWake { line: usize },
+    OverlappingShifts { line: usize, guard: i32, other: i32 },
+}
+
+impl fmt::Display for LogError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LogError::Unknown { line, text } => write!(f, "line {line}: unknown entry {text:?}"),
+            LogError::SleepWithoutGuard { line } => {
+                write!(f, "line {line}: falls asleep before any shift began")
+            }
+            LogError::WakeWithoutSleep { line } => {
+                write!(f, "line {line}: wakes up without having fallen asleep")
+            }
+            LogError::SleepWhileAsleep { line, asleep_since } => write!(
+                f,
+                "line {line}: falls asleep while already asleep since line {asleep_since}"
+            ),
+            LogError::SleepWithoutWake { line } => {
+                write!(f, "line {line}: falls asleep and never wakes up")
+            }
+            LogError::OverlappingShifts { line, guard, other } => write!(
+                f,
+                "line {line}: guard #{guard} begins a shift on the same night as guard #{other}"
+            ),
+        }
+    }
+}
+
+// "[1518-11-01 23:58] Guard #99 begins shift", "[1518-11-02 00:40] falls asleep", ...
+fn parse_entry(text: &str, line: usize) -> Result<(Timestamp, Entry), LogError> {
+    let unknown = || LogError::Unknown {
+        line,
+        text: text.to_string(),
+    };
+    let (stamp, message) = text
+        .strip_prefix('[')
+        .and_then(|t| t.split_once("] "))
+        .ok_or_else(unknown)?;
+
+    let (mut year, mut month, mut day, mut hour, mut minute) = (0, 0, 0, 0, 0);
+    sscanf!(stamp, "{}-{}-{} {}:{}", year, month, day, hour, minute).map_err(|_| unknown())?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour >= 24 || minute >= 60 {
+        return Err(unknown());
+    }
+
+    let mut guard = 0;
+    let entry = if sscanf!(message, "Guard #{} begins shift", guard).is_ok() {
+        Entry::BeginsShift(guard)
+    } else if message == "falls asleep" {
+        Entry::FallsAsleep
+    } else if message == "wakes up" {
+        Entry::WakesUp
+    } else {
+        return Err(unknown());
+    };
+
+    Ok((Timestamp::new(year, month, day, hour, minute), entry))
+}
+
+#[derive(Debug, Clone)]
+struct Shift {
+    guard: i32,
+    night: i64,
+    // Minutes of the midnight hour the guard slept through
+    sleeps: Vec<Interval>,
+}
+
+// Shifts in chronological order; sleeps are clamped to the midnight hour, which is all that
+// matters, and every clamped one is described in the warnings
+fn parse_guard_log(
+    lines: impl Iterator<Item = String>,
+) -> Result<(Vec<Shift>, Vec<String>), LogError> {
+    let mut entries = Vec::new();
+    for (i, text) in lines.enumerate().filter(|(_, l)| !l.trim().is_empty()) {
+        let (time, entry) = parse_entry(text.trim(), i + 1)?;
+        entries.push((time, i + 1, entry));
+    }
+    entries.sort_by_key(|&(time, line, _)| (time, line));
+
+    let mut shifts: Vec<Shift> = Vec::new();
+    let mut warnings = Vec::new();
+    let mut asleep_since: Option<(Timestamp, usize)> = None;
+    for (time, line, entry) in entries {
+        match entry {
+            Entry::BeginsShift(guard) => {
+                if let Some((_, sleep_line)) = asleep_since {
+                    return Err(LogError::SleepWithoutWake { line: sleep_line });
+                }
+                if let Some(last) = shifts.last().filter(|s| s.night == time.night()) {
+                    let other = last.guard;
+                    return Err(LogError::OverlappingShifts { line, guard, other });
+                }
+                shifts.push(Shift {
+                    guard,
+                    night: time.night(),
+                    sleeps: Vec::new(),
                 });
+            }
+            Entry::FallsAsleep => {
+                if shifts.is_empty() {
+                    return Err(LogError::SleepWithoutGuard { line });
+                }
+                if let Some((_, since)) = asleep_since {
+                    return Err(LogError::SleepWhileAsleep {
+                        line,
+                        asleep_since: since,
+                    });
+                }
+                asleep_since = Some((time, line));
+            }
+            Entry::WakesUp => {
+                let Some((start, start_line)) = asleep_since.take() else {
+                    return Err(LogError::WakeWithoutSleep { line });
+                };
+                let shift = shifts.last_mut().unwrap();
+                let (from, to) = (start.minute_of(shift.night), time.minute_of(shift.night));
+                let (min, max) = (from.clamp(0, 60), to.clamp(0, 60));
+                if (min, max) != (from, to) {
+                    warnings.push(format!(
+                        "lines {start_line}-{line}: sleep outside the midnight hour clamped to \
+                         00:{min:02}-{:02}:{:02}",
+                        max / 60,
+                        max % 60
+                    ));
+                }
+                if min < max {
+                    shift.sleeps.push(Interval {
+                        min: min as i32,
+                        max: max as i32,
+                    });
+                }
+            }
         }
     }
+    if let Some((_, line)) = asleep_since {
+        return Err(LogError::SleepWithoutWake { line });
+    }
 
-    return guard_sleep_intervals;
+    Ok((shifts, warnings))
 }
 
-fn part_one() {
-    let guards_data = read_guards_data();
-    let (guard, most_slept_minute) = find_guard_most_asleep(&guards_data);
-    let result = guard * most_slept_minute;
-    println!("{result}");
+fn read_shifts() -> Vec<Shift> {
+    let (shifts, warnings) =
+        parse_guard_log(stdin().lines().map(|l| l.unwrap())).unwrap_or_else(|e| {
+            eprintln!("invalid guard log: {e}");
+            process::exit(1);
+        });
+    for w in warnings {
+        eprintln!("{w}");
+    }
+
+    shifts
+}
+
+// Guards who never slept are left out
+fn sleep_intervals(shifts: &[Shift]) -> HashMap<i32, Vec<Interval>> {
+    let mut guard_sleep_intervals = HashMap::new();
+    for shift in shifts.iter().filter(|s| !s.sleeps.is_empty()) {
+        guard_sleep_intervals
+            .entry(shift.guard)
+            .or_insert(Vec::new())
+            .extend(&shift.sleeps);
+    }
+
+    guard_sleep_intervals
 }
 
 fn times_asleep_at_minute(intervals: &Vec<Interval>, minute: i32) -> usize {
@@ -96,28 +277,187 @@ fn most_times_asleep_any_minute(intervals: &Vec<Interval>) -> usize {
         .unwrap();
 }
 
+// Same as `find_guard_most_asleep`, ties included
 fn find_guard_most_asleep_on_same_minute(
     guard_sleep_intervals: &HashMap<i32, Vec<Interval>>,
-) -> (i32, i32) {
-    let guard = *guard_sleep_intervals
-        .keys()
-        .max_by_key(|&guard| most_times_asleep_any_minute(&guard_sleep_intervals[guard]))
-        .unwrap();
+) -> Option<(i32, i32)> {
+    let guard = *guard_sleep_intervals.keys().max_by_key(|&guard| {
+        (
+            most_times_asleep_any_minute(&guard_sleep_intervals[guard]),
+            Reverse(*guard),
+        )
+    })?;
 
     let minute = (0..60)
-        .max_by_key(|minute| times_asleep_at_minute(&guard_sleep_intervals[&guard], *minute))
+        .max_by_key(|&minute| {
+            (
+                times_asleep_at_minute(&guard_sleep_intervals[&guard], minute),
+                Reverse(minute),
+            )
+        })
         .unwrap();
 
-    return (guard, minute);
+    Some((guard, minute))
+}
+
+// How many times each guard was asleep at each minute of the midnight hour
+fn histograms(shifts: &[Shift]) -> BTreeMap<i32, [usize; 60]> {
+    let mut result = BTreeMap::new();
+    for shift in shifts {
+        let histogram = result.entry(shift.guard).or_insert([0; 60]);
+        for interval in &shift.sleeps {
+            for minute in interval.min..interval.max {
+                histogram[minute as usize] += 1;
+            }
+        }
+    }
+
+    result
+}
+
+fn most_slept_minute(histogram: &[usize; 60]) -> i32 {
+    (0..60).max_by_key(|&m| (histogram[m], Reverse(m))).unwrap() as i32
+}
+
+// A way of picking a guard and the minute to sneak in at, None if nobody ever slept. In every
+// strategy ties go to the lowest guard id and the earliest minute.
+trait Strategy {
+    fn name(&self) -> &'static str;
+    fn choose(&self, shifts: &[Shift]) -> Option<(i32, i32)>;
+}
+
+struct MostAsleep;
+
+impl Strategy for MostAsleep {
+    fn name(&self) -> &'static str {
+        "most minutes asleep"
+    }
+
+    fn choose(&self, shifts: &[Shift]) -> Option<(i32, i32)> {
+        find_guard_most_asleep(&sleep_intervals(shifts))
+    }
+}
+
+struct MostAsleepOnSameMinute;
+
+impl Strategy for MostAsleepOnSameMinute {
+    fn name(&self) -> &'static str {
+        "most asleep on the same minute"
+    }
+
+    fn choose(&self, shifts: &[Shift]) -> Option<(i32, i32)> {
+        find_guard_most_asleep_on_same_minute(&sleep_intervals(shifts))
+    }
+}
+
+// The guard asleep at their favourite minute on the largest share of their shifts
+struct MostConsistent;
+
+impl Strategy for MostConsistent {
+    fn name(&self) -> &'static str {
+        "most consistent sleeper"
+    }
+
+    fn choose(&self, shifts: &[Shift]) -> Option<(i32, i32)> {
+        let mut shift_counts: HashMap<i32, usize> = HashMap::new();
+        for shift in shifts {
+            *shift_counts.entry(shift.guard).or_insert(0) += 1;
+        }
+
+        let (guard, histogram) = histograms(shifts)
+            .into_iter()
+            .filter(|(_, h)| h.iter().any(|&c| c > 0))
+            .max_by(|(g1, h1), (g2, h2)| {
+                let share = |g, h: &[usize; 60]| {
+                    h[most_slept_minute(h) as usize] as f64 / shift_counts[g] as f64
+                };
+                share(g1, h1)
+                    .total_cmp(&share(g2, h2))
+                    .then(shift_counts[g1].cmp(&shift_counts[g2]))
+                    .then(g2.cmp(g1))
+            })?;
+
+        Some((guard, most_slept_minute(&histogram)))
+    }
+}
+
+// The guard who fell asleep on the most nights
+struct MostNightsAsleep;
+
+impl Strategy for MostNightsAsleep {
+    fn name(&self) -> &'static str {
+        "most nights asleep"
+    }
+
+    fn choose(&self, shifts: &[Shift]) -> Option<(i32, i32)> {
+        let mut nights: HashMap<i32, HashSet<i64>> = HashMap::new();
+        for shift in shifts.iter().filter(|s| !s.sleeps.is_empty()) {
+            nights.entry(shift.guard).or_default().insert(shift.night);
+        }
+
+        let guard = *nights
+            .keys()
+            .max_by_key(|&g| (nights[g].len(), Reverse(*g)))?;
+        Some((guard, most_slept_minute(&histograms(shifts)[&guard])))
+    }
+}
+
+fn strategies() -> Vec<Box<dyn Strategy>> {
+    vec![
+        Box::new(MostAsleep),
+        Box::new(MostAsleepOnSameMinute),
+        Box::new(MostConsistent),
+        Box::new(MostNightsAsleep),
+    ]
+}
+
+const NOBODY_SLEEPS: &str = "no guard ever falls asleep";
+
+fn part_one() {
+    match MostAsleep.choose(&read_shifts()) {
+        Some((guard, most_slept_minute)) => println!("{}", guard * most_slept_minute),
+        None => println!("{NOBODY_SLEEPS}"),
+    }
 }
 
 fn part_two() {
-    let guards_data = read_guards_data();
-    let (guard, most_frequently_slept_minute) = find_guard_most_asleep_on_same_minute(&guards_data);
-    let result = guard * most_frequently_slept_minute;
-    println!("{result}");
+    match MostAsleepOnSameMinute.choose(&read_shifts()) {
+        Some((guard, most_frequently_slept_minute)) => {
+            println!("{}", guard * most_frequently_slept_minute)
+        }
+        None => println!("{NOBODY_SLEEPS}"),
+    }
+}
+
+// Every strategy's pick on the same log
+fn compare_strategies() {
+    let shifts = read_shifts();
+    for strategy in strategies() {
+        match strategy.choose(&shifts) {
+            Some((guard, minute)) => println!(
+                "{:<32} guard #{guard:<5} minute {minute:<3} {}",
+                strategy.name(),
+                guard * minute
+            ),
+            None => println!("{:<32} {NOBODY_SLEEPS}", strategy.name()),
+        }
+    }
+}
+
+fn print_histograms() {
+    let header = (0..60).map(|m| format!("00:{m:02}")).collect::<Vec<_>>();
+    println!("guard,{}", header.join(","));
+    for (guard, histogram) in histograms(&read_shifts()) {
+        let counts = histogram.map(|c| c.to_string());
+        println!("{guard},{}", counts.join(","));
+    }
 }
 
 fn main() {
-    part_two();
+    match env::args().nth(1).as_deref() {
+        Some("part_one") => part_one(),
+        Some("strategies") => compare_strategies(),
+        Some("histogram") => print_histograms(),
+        _ => part_two(),
+    }
 }
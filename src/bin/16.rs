@@ -1,78 +1,32 @@
-use std::{collections::HashMap, collections::HashSet, io::stdin};
+use std::{collections::HashMap, collections::HashSet, env, io::stdin};
 
+use advent_2018::elfcode::{
+    isa::{register_division, Operand},
+    rng::Rng,
+    try_instr, Instr, OpCode,
+};
 use scanf::sscanf;
-use std::slice::Iter;
-
-#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
-enum OpCode {
-    Addr,
-    Addi,
-    Mulr,
-    Muli,
-    Banr,
-    Bani,
-    Borr,
-    Bori,
-    Setr,
-    Seti,
-    Gtir,
-    Gtri,
-    Gtrr,
-    Eqir,
-    Eqri,
-    Eqrr,
-}
-
-impl OpCode {
-    pub fn iterator() -> Iter<'static, OpCode> {
-        static opcodes: [OpCode; 16] = [
-            OpCode::Addr,
-            OpCode::Addi,
-            OpCode::Mulr,
-            OpCode::Muli,
-            OpCode::Banr,
-            OpCode::Bani,
-            OpCode::Borr,
-            OpCode::Bori,
-            OpCode::Setr,
-            OpCode::Seti,
-            OpCode::Gtir,
-            OpCode::Gtri,
-            OpCode::Gtrr,
-            OpCode::Eqir,
-            OpCode::Eqri,
-            OpCode::Eqrr,
-        ];
-        return opcodes.iter();
-    }
-}
 
 const registers_cnt: usize = 4;
 type State = [i32; registers_cnt];
 
-fn apply(state: State, opcode: OpCode, a: i32, b: i32, c: i32) -> State {
-    let mut result = state;
-    let (asz, bsz, csz) = (a as usize, b as usize, c as usize);
-    match opcode {
-        OpCode::Addr => result[csz] = result[asz] + result[bsz],
-        OpCode::Addi => result[csz] = result[asz] + b,
-        OpCode::Mulr => result[csz] = result[asz] * result[bsz],
-        OpCode::Muli => result[csz] = result[asz] * b,
-        OpCode::Banr => result[csz] = result[asz] & result[bsz],
-        OpCode::Bani => result[csz] = result[asz] & b,
-        OpCode::Borr => result[csz] = result[asz] | result[bsz],
-        OpCode::Bori => result[csz] = result[asz] | b,
-        OpCode::Setr => result[csz] = result[asz],
-        OpCode::Seti => result[csz] = a,
-        OpCode::Gtir => result[csz] = if a > result[bsz] { 1 } else { 0 },
-        OpCode::Gtri => result[csz] = if result[asz] > b { 1 } else { 0 },
-        OpCode::Gtrr => result[csz] = if result[asz] > result[bsz] { 1 } else { 0 },
-        OpCode::Eqir => result[csz] = if a == result[bsz] { 1 } else { 0 },
-        OpCode::Eqri => result[csz] = if result[asz] == b { 1 } else { 0 },
-        OpCode::Eqrr => result[csz] = if result[asz] == result[bsz] { 1 } else { 0 },
+// None if the instruction can't be applied to `state`, e.g. a registered division by zero
+fn apply(state: State, opcode: OpCode, a: i32, b: i32, c: i32) -> Option<State> {
+    let mut rs = state.map(|v| v as i64);
+    let instr = Instr {
+        code: opcode,
+        op1: a,
+        op2: b,
+        op3: c,
+    };
+    if !try_instr(instr, &mut rs) {
+        return None;
     }
 
-    result
+    rs.map(|v| v.try_into().ok())
+        .into_iter()
+        .collect::<Option<Vec<_>>>()
+        .map(vec_to_state)
 }
 
 type EncodedInstr = [i32; 4];
@@ -159,10 +113,10 @@ fn possible_opcodes(before_after: BeforeAfterPair) -> Vec<OpCode> {
         before_after.instr[3],
     );
 
-    for opcode in OpCode::iterator() {
-        let new_state = apply(before_after.before, *opcode, a, b, c);
-        if new_state == before_after.after {
-            result.push(*opcode);
+    for opcode in OpCode::all() {
+        let new_state = apply(before_after.before, opcode, a, b, c);
+        if new_state == Some(before_after.after) {
+            result.push(opcode);
         }
     }
 
@@ -181,57 +135,371 @@ fn part_one() {
     println!("{result}");
 }
 
-fn part_two() {
-    let input = read_input();
-    let mut mapping: HashMap<i32, HashSet<OpCode>> = HashMap::new();
-    let all_opcodes = OpCode::iterator().map(|&v| v).collect::<HashSet<_>>();
+type Candidates = HashMap<i32, HashSet<OpCode>>;
+type Assignment = HashMap<i32, OpCode>;
+
+// Intersects the possible opcodes of the samples per numeric code, leaving out the ones in `skip`
+fn intersect(pairs: &[BeforeAfterPair], options: &[Vec<OpCode>], skip: &[usize]) -> Candidates {
+    let mut candidates: Candidates = HashMap::new();
+    let all_opcodes = OpCode::all().into_iter().collect::<HashSet<_>>();
+    for (i, pair) in pairs.iter().enumerate() {
+        if !skip.contains(&i) {
+            candidates
+                .entry(pair.instr[0])
+                .or_insert(all_opcodes.clone())
+                .retain(|o| options[i].contains(o));
+        }
+    }
 
-    for pair in input.pairs {
-        let p = possible_opcodes(pair);
-        let instr_code = pair.instr[0];
+    candidates
+}
+
+// The assignment of distinct opcodes to codes the most samples agree with, found with the
+// Hungarian algorithm; with more codes than opcodes, the codes left over are missing from it
+fn best_assignment(pairs: &[BeforeAfterPair], options: &[Vec<OpCode>]) -> Assignment {
+    let opcodes = OpCode::all();
+    let mut codes = pairs.iter().map(|p| p.instr[0]).collect::<Vec<_>>();
+    codes.sort();
+    codes.dedup();
+
+    // Square, padded with rows and columns nobody agrees with; costs are negated agreements
+    let n = codes.len().max(opcodes.len());
+    let mut cost = vec![vec![0i64; n + 1]; n + 1];
+    for (pair, options) in pairs.iter().zip(options) {
+        let row = codes.binary_search(&pair.instr[0]).unwrap() + 1;
+        for (col, opcode) in opcodes.iter().enumerate() {
+            if options.contains(opcode) {
+                cost[row][col + 1] -= 1;
+            }
+        }
+    }
 
-        mapping
-            .entry(instr_code)
-            .or_insert(all_opcodes.clone())
-            .retain(|item| p.contains(item));
+    // `row_of[col]` is the row assigned to column `col`, 0 for none; index 0 is a sentinel
+    let (mut u, mut v) = (vec![0i64; n + 1], vec![0i64; n + 1]);
+    let (mut row_of, mut way) = (vec![0; n + 1], vec![0; n + 1]);
+    for row in 1..=n {
+        row_of[0] = row;
+        let mut col = 0;
+        let mut min = vec![i64::MAX; n + 1];
+        let mut used = vec![false; n + 1];
+        while row_of[col] != 0 {
+            used[col] = true;
+            let current = row_of[col];
+            let (mut delta, mut next) = (i64::MAX, 0);
+            for j in (1..=n).filter(|&j| !used[j]) {
+                let reduced = cost[current][j] - u[current] - v[j];
+                if reduced < min[j] {
+                    (min[j], way[j]) = (reduced, col);
+                }
+                if min[j] < delta {
+                    (delta, next) = (min[j], j);
+                }
+            }
+            for j in 0..=n {
+                if used[j] {
+                    u[row_of[j]] += delta;
+                    v[j] -= delta;
+                } else {
+                    min[j] -= delta;
+                }
+            }
+            col = next;
+        }
+        while col != 0 {
+            let previous = way[col];
+            row_of[col] = row_of[previous];
+            col = previous;
+        }
     }
 
-    let mut did_elimination: HashSet<i32> = HashSet::new();
+    (1..=opcodes.len())
+        .filter(|&col| (1..=codes.len()).contains(&row_of[col]))
+        .map(|col| (codes[row_of[col] - 1], opcodes[col - 1]))
+        .collect()
+}
 
+// The candidates per numeric code. When no assignment agrees with every sample, the samples that
+// disagree with the one the most samples agree with are left out and reported by index.
+fn sample_constraints(pairs: &[BeforeAfterPair]) -> (Candidates, Vec<usize>) {
+    let options = pairs
+        .iter()
+        .map(|&pair| possible_opcodes(pair))
+        .collect::<Vec<_>>();
+    let candidates = intersect(pairs, &options, &[]);
+    let mut solutions = Vec::new();
+    solve(candidates.clone(), 1, &mut solutions);
+    if !solutions.is_empty() {
+        return (candidates, Vec::new());
+    }
+
+    let best = best_assignment(pairs, &options);
+    let inconsistent = (0..pairs.len())
+        .filter(|&i| {
+            best.get(&pairs[i].instr[0])
+                .is_none_or(|opcode| !options[i].contains(opcode))
+        })
+        .collect::<Vec<_>>();
+
+    (intersect(pairs, &options, &inconsistent), inconsistent)
+}
+
+// Removes the opcode of every decided code from all the others until nothing changes; returns
+// false once some code has no candidate left
+fn propagate(candidates: &mut Candidates) -> bool {
     loop {
-        let determined = mapping
+        let decided = candidates
             .iter()
-            .filter(|(&k, v)| v.len() == 1 && !did_elimination.contains(&k))
-            .map(|(&u, v)| (u, v.clone()))
-            .next();
+            .filter(|(_, v)| v.len() == 1)
+            .map(|(&k, v)| (k, *v.iter().next().unwrap()))
+            .collect::<Vec<_>>();
+
+        let mut changed = false;
+        for (k, opcode) in decided {
+            for (_, options) in candidates.iter_mut().filter(|(&other, _)| other != k) {
+                changed |= options.remove(&opcode);
+            }
+        }
 
-        if determined.is_none() {
-            break;
+        if candidates.values().any(|v| v.is_empty()) {
+            return false;
         }
-        if let Some((k, options)) = determined {
-            let option = options.iter().next().unwrap();
-            let keys = mapping.keys().map(|&u| u).collect::<Vec<_>>();
-            for other in keys {
-                if other != k {
-                    mapping.entry(other).and_modify(|v| {
-                        v.remove(option);
-                    });
-                }
+        if !changed {
+            return true;
+        }
+    }
+}
+
+// Every assignment of distinct opcodes to the codes allowed by `candidates`, up to `max_solutions`
+fn solve(mut candidates: Candidates, max_solutions: usize, solutions: &mut Vec<Assignment>) {
+    if solutions.len() >= max_solutions || !propagate(&mut candidates) {
+        return;
+    }
+
+    let undecided = candidates
+        .iter()
+        .filter(|(_, v)| v.len() > 1)
+        .min_by_key(|(&k, v)| (v.len(), k))
+        .map(|(&k, v)| (k, v.clone()));
+
+    match undecided {
+        None => solutions.push(
+            candidates
+                .iter()
+                .map(|(&k, v)| (k, *v.iter().next().unwrap()))
+                .collect(),
+        ),
+        Some((code, options)) => {
+            let mut options = options.into_iter().collect::<Vec<_>>();
+            options.sort();
+            for option in options {
+                let mut guess = candidates.clone();
+                guess.insert(code, HashSet::from([option]));
+                solve(guess, max_solutions, solutions);
             }
+        }
+    }
+}
 
-            did_elimination.insert(k);
+// One line per code, listing every opcode it has in some solution
+fn mapping_table(solutions: &[Assignment]) -> String {
+    let mut codes = solutions
+        .iter()
+        .flat_map(|s| s.keys().copied())
+        .collect::<Vec<_>>();
+    codes.sort();
+    codes.dedup();
+
+    let mut result = String::new();
+    for code in codes {
+        let mut options = solutions.iter().map(|s| s[&code]).collect::<Vec<_>>();
+        options.sort();
+        options.dedup();
+        let options = options
+            .into_iter()
+            .map(OpCode::mnemonic)
+            .collect::<Vec<_>>();
+        result.push_str(&format!("{code:>2} -> {}\n", options.join(" | ")));
+    }
+
+    result
+}
+
+// None if some instruction can't be applied, see `apply`, or has a code missing from `mapping`
+fn run(program: &[EncodedInstr], mapping: &Assignment) -> Option<State> {
+    let mut state = [0; registers_cnt];
+    for instr in program {
+        let opcode = *mapping.get(&instr[0])?;
+        state = apply(state, opcode, instr[1], instr[2], instr[3])?;
+    }
+
+    Some(state)
+}
+
+const MAX_SOLUTIONS: usize = 1000;
+
+fn part_two() {
+    let input = read_input();
+    let (mut candidates, inconsistent) = sample_constraints(&input.pairs);
+    for &i in &inconsistent {
+        println!("inconsistent sample {i}: {:?}", input.pairs[i]);
+    }
+    // Codes only the test program uses can be any opcode the samples leave over
+    let mut uncovered = input
+        .program
+        .iter()
+        .map(|instr| instr[0])
+        .filter(|code| !candidates.contains_key(code))
+        .collect::<Vec<_>>();
+    uncovered.sort();
+    uncovered.dedup();
+    for code in uncovered {
+        println!("code {code} isn't covered by any sample");
+        candidates.insert(code, OpCode::all().into_iter().collect());
+    }
+
+    let mut solutions = Vec::new();
+    solve(candidates, MAX_SOLUTIONS, &mut solutions);
+    match solutions.len() {
+        0 => {
+            println!("no consistent opcode assignment");
+            return;
+        }
+        1 => {}
+        MAX_SOLUTIONS => println!("at least {MAX_SOLUTIONS} consistent opcode assignments"),
+        n => println!("{n} consistent opcode assignments"),
+    }
+    print!("{}", mapping_table(&solutions));
+
+    let mut results = solutions
+        .iter()
+        .map(|s| run(&input.program, s).map(|state| state[0]))
+        .collect::<Vec<_>>();
+    results.sort();
+    results.dedup();
+    if let [Some(result)] = results[..] {
+        println!("{result}");
+    } else {
+        println!("register 0 depends on the assignment: {results:?}");
+    }
+}
+
+const VALUE_RANGE: usize = 8;
+const IMMEDIATE_RANGE: usize = 64;
+// Attempts at drawing a sample of the wanted kind before settling for any
+const MAX_ATTEMPTS: usize = 1000;
+
+fn random_sample(rng: &mut Rng, secret: &[OpCode], code: usize) -> BeforeAfterPair {
+    loop {
+        let before = [(); registers_cnt].map(|_| rng.below(VALUE_RANGE) as i32);
+        let mut operand = || rng.below(registers_cnt) as i32;
+        let instr = [code as i32, operand(), operand(), operand()];
+
+        if let Some(after) = apply(before, secret[code], instr[1], instr[2], instr[3]) {
+            return BeforeAfterPair {
+                before,
+                instr,
+                after,
+            };
         }
     }
+}
 
+// Samples and a test program in the puzzle's input format, encoded with the numbering `secret`
+// (code i is `secret[i]`); each sample matches more than one opcode with probability
+// `ambiguous_percent` as far as the random draws allow
+fn generate(
+    rng: &mut Rng,
+    secret: &[OpCode],
+    samples: usize,
+    ambiguous_percent: usize,
+    program_len: usize,
+) -> (String, Vec<EncodedInstr>) {
+    let mut result = String::new();
+    for _ in 0..samples {
+        let code = rng.below(secret.len());
+        let ambiguous = rng.below(100) < ambiguous_percent;
+        let mut sample = random_sample(rng, secret, code);
+        for _ in 0..MAX_ATTEMPTS {
+            if (possible_opcodes(sample).len() > 1) == ambiguous {
+                break;
+            }
+            sample = random_sample(rng, secret, code);
+        }
+        result.push_str(&format!(
+            "Before: {:?}\n{}\nAfter:  {:?}\n\n",
+            sample.before,
+            sample.instr.map(|v| v.to_string()).join(" "),
+            sample.after
+        ));
+    }
+
+    // Values are kept small so that the program can't overflow. Immediates span a wider range
+    // than registers and the last instruction leaves something other than a truth value in
+    // register 0, so that a wrong numbering is unlikely to give the expected answer.
+    let mut program = Vec::new();
     let mut state = [0; registers_cnt];
-    for instr in input.program {
-        let opcode = *mapping[&instr[0]].iter().next().unwrap();
-        state = apply(state, opcode, instr[1], instr[2], instr[3]);
+    result.push_str("\n\n");
+    while program.len() < program_len {
+        let code = rng.below(secret.len());
+        let last = program.len() + 1 == program_len;
+        let [a, b] = secret[code].operands().map(|kind| match kind {
+            Operand::Immediate => rng.below(IMMEDIATE_RANGE) as i32,
+            _ => rng.below(registers_cnt) as i32,
+        });
+        let c = if last {
+            0
+        } else {
+            rng.below(registers_cnt) as i32
+        };
+        let instr = [code as i32, a, b, c];
+        let next = apply(state, secret[code], a, b, c)
+            .filter(|n| n.iter().all(|v| v.abs() < 1 << 16))
+            .filter(|n| !last || n[0].abs() > 1);
+        if let Some(next) = next {
+            state = next;
+            program.push(instr);
+            result.push_str(&format!("{}\n", instr.map(|v| v.to_string()).join(" ")));
+        }
     }
 
-    println!("{}", state[0]);
+    (result, program)
 }
 
+// generate <seed> <samples> <ambiguous percent> <program length>: prints a puzzle input, the
+// secret numbering and the expected answer go to stderr
+fn print_generated() {
+    let args = env::args()
+        .skip(2)
+        .filter(|a| !a.starts_with("--"))
+        .map(|a| a.parse::<usize>().expect("arguments should be numbers"))
+        .collect::<Vec<_>>();
+    let [seed, samples, ambiguous_percent, program_len] = args[..] else {
+        panic!("usage: generate <seed> <samples> <ambiguous percent> <program length>");
+    };
+
+    let mut rng = Rng::new(seed as u64);
+    let mut secret = OpCode::all().into_iter().collect::<Vec<_>>();
+    rng.shuffle(&mut secret);
+    let (input, program) = generate(&mut rng, &secret, samples, ambiguous_percent, program_len);
+
+    let mapping = secret
+        .iter()
+        .enumerate()
+        .map(|(code, &opcode)| (code as i32, opcode))
+        .collect::<Assignment>();
+    let expected = run(&program, &mapping).unwrap()[0];
+    print!("{input}");
+    eprint!("{}", mapping_table(&[mapping]));
+    eprintln!("{expected}");
+}
+
+// `--division` adds `divr`, `divi`, `modr` and `modi` to the candidate opcodes
 fn main() {
-    part_two();
+    if env::args().any(|a| a == "--division") {
+        register_division();
+    }
+    match env::args().nth(1).as_deref() {
+        Some("generate") => print_generated(),
+        _ => part_two(),
+    }
 }
@@ -1,102 +1,17 @@
-use std::{
-    any::Any,
-    collections::{btree_map::VacantEntry, hash_map::OccupiedEntry, HashMap},
-    io::stdin,
-    vec,
+use std::{env, io::stdin, vec};
+
+use advent_2018::elfcode::{
+    debugger::{repl, Debugger},
+    decompile::decompile,
+    equivalence::{elfcode_runner, find_difference, initial_states},
+    execute,
+    io::Device,
+    profile::{annotated_listing, profile},
+    read_program, read_program_file,
+    transpile::{build_and_run, transpile},
+    Vm, REGISTERS_CNT,
 };
 
-use scanf::sscanf;
-use OpCode::*;
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum OpCode {
-    Addr,
-    Addi,
-    Mulr,
-    Muli,
-    Banr,
-    Bani,
-    Borr,
-    Bori,
-    Setr,
-    Seti,
-    Gtir,
-    Gtri,
-    Gtrr,
-    Eqir,
-    Eqri,
-    Eqrr,
-}
-
-fn parse_opcode(s: &str) -> OpCode {
-    match s {
-        "addr" => Addr,
-        "addi" => Addi,
-        "mulr" => Mulr,
-        "muli" => Muli,
-        "banr" => Banr,
-        "bani" => Bani,
-        "borr" => Borr,
-        "bori" => Bori,
-        "setr" => Setr,
-        "seti" => Seti,
-        "gtir" => Gtir,
-        "gtri" => Gtri,
-        "gtrr" => Gtrr,
-        "eqir" => Eqir,
-        "eqri" => Eqri,
-        "eqrr" => Eqrr,
-        _ => todo!(),
-    }
-}
-
-#[derive(Debug, Clone, Copy)]
-struct Instr {
-    code: OpCode,
-    op1: i32,
-    op2: i32,
-    op3: i32,
-}
-
-fn do_instr(instr: Instr, rs: &mut Vec<i64>) {
-    let Instr {
-        code,
-        op1: a,
-        op2: b,
-        op3: c,
-    } = instr;
-    match code {
-        Addr => rs[c as usize] = rs[a as usize] + rs[b as usize],
-        Addi => rs[c as usize] = rs[a as usize] + b as i64,
-        Mulr => rs[c as usize] = rs[a as usize] * rs[b as usize],
-        Muli => rs[c as usize] = rs[a as usize] * b as i64,
-        Banr => rs[c as usize] = rs[a as usize] & rs[b as usize],
-        Bani => rs[c as usize] = rs[a as usize] & b as i64,
-        Borr => rs[c as usize] = rs[a as usize] | rs[b as usize],
-        Bori => rs[c as usize] = rs[a as usize] | b as i64,
-        Setr => rs[c as usize] = rs[a as usize],
-        Seti => rs[c as usize] = a as i64,
-        Gtir => rs[c as usize] = if a as i64 > rs[b as usize] { 1 } else { 0 },
-        Gtri => rs[c as usize] = if rs[a as usize] > b as i64 { 1 } else { 0 },
-        Gtrr => {
-            rs[c as usize] = if rs[a as usize] > rs[b as usize] {
-                1
-            } else {
-                0
-            }
-        }
-        Eqir => rs[c as usize] = if a as i64 == rs[b as usize] { 1 } else { 0 },
-        Eqri => rs[c as usize] = if rs[a as usize] == b as i64 { 1 } else { 0 },
-        Eqrr => {
-            rs[c as usize] = if rs[a as usize] == rs[b as usize] {
-                1
-            } else {
-                0
-            }
-        }
-    }
-}
-
 fn eq_excluding(v1: &Vec<i64>, v2: &Vec<i64>, idx: usize) -> bool {
     if v1.len() != v2.len() {
         return false;
@@ -111,89 +26,140 @@ fn eq_excluding(v1: &Vec<i64>, v2: &Vec<i64>, idx: usize) -> bool {
     true
 }
 
-const REGISTERS_CNT: usize = 6;
-fn execute(
-    program: &Vec<Instr>,
-    ip_reg: usize,
-    start_state: &Vec<i64>,
-    instructions_limit: Option<usize>,
-) -> Vec<i64> {
-    let mut registers = start_state.clone();
-    let mut ip = 0;
-
-    let mut cycles = 0;
-
-    loop {
-        if ip as usize >= program.len()
-            || (instructions_limit.is_some() && cycles >= instructions_limit.unwrap())
-        {
-            break;
-        }
-        let instr = program[ip as usize];
-        registers[ip_reg] = ip;
+fn part_one() {
+    let (instructions, ip_register) = read_program();
+    let final_state = execute(&instructions, ip_register, &vec![0; REGISTERS_CNT], None);
+
+    println!("{}", final_state[0]);
+}
 
-        do_instr(instr, &mut registers);
-        ip = registers[ip_reg];
-        ip += 1;
+fn part_one_checked() {
+    let (instructions, ip_register) = read_program();
+    let mut vm = Vm::new(&instructions, ip_register, &[0; REGISTERS_CNT]);
 
-        cycles += 1;
+    match vm.run_checked(Some(CYCLE_BUDGET)) {
+        Ok(()) => println!("{}", vm.registers[0]),
+        Err(e) => println!("{e}"),
     }
-
-    registers
 }
 
-fn read_input() -> (Vec<Instr>, usize) {
-    let mut ip_register = 0;
-    let mut instructions = Vec::new();
-    for line in stdin().lines().map(|l| l.unwrap()) {
-        let mut ip_reg = 0;
-        let (mut instr_type, mut op1, mut op2, mut op3) = (String::new(), 0, 0, 0);
-        if sscanf!(&line, "ip {}", ip_reg).is_ok() {
-            ip_register = ip_reg;
-        } else if sscanf!(&line, "{} {} {} {}", instr_type, op1, op2, op3).is_ok() {
-            let instr_type = parse_opcode(&instr_type);
-            instructions.push(Instr {
-                code: instr_type,
-                op1: op1,
-                op2: op2,
-                op3: op3,
-            });
-        }
-    }
+fn part_two() {
+    let (instructions, ip_register) = read_program();
+    let mut start_state = vec![0; REGISTERS_CNT];
+    start_state[0] = 1;
 
-    (instructions, ip_register as usize)
+    let mut vm = Vm::new(&instructions, ip_register, &start_state);
+    vm.accelerate();
+    vm.run(None);
+
+    println!("{}", vm.registers[0]);
 }
 
-fn part_one() {
-    let (instructions, ip_register) = read_input();
-    let final_state = execute(&instructions, ip_register, &vec![0; REGISTERS_CNT], None);
+fn print_transpiled() {
+    let (instructions, ip_register) = read_program();
+    print!("{}", transpile(&instructions, ip_register));
+}
 
-    println!("{}", final_state[0]);
+// Runs part one both natively and in the interpreter, they should agree on every register and
+// on the number of executed instructions
+fn transpile_check() {
+    let (instructions, ip_register) = read_program();
+    let start_state = [0; REGISTERS_CNT];
+    let (native, native_cycles) =
+        build_and_run(&transpile(&instructions, ip_register), &start_state).unwrap();
+
+    let mut vm = Vm::new(&instructions, ip_register, &start_state);
+    vm.run(None);
+    if native == vm.registers && native_cycles == vm.cycles {
+        println!("ok: {native:?} after {native_cycles} instructions");
+    } else {
+        println!(
+            "mismatch: native {native:?} after {native_cycles}, interpreter {:?} after {}",
+            vm.registers, vm.cycles
+        );
+    }
 }
 
-fn sum_divisors(n: u64) -> u64 {
-    let mut result = 0;
-    for i in 1..=n {
-        if n % i == 0 {
-            result += i;
-        }
+// Compares the program with a hand-edited version from the file given after `equiv`, starting
+// from edge and random values of register 0
+fn check_equivalence() {
+    let path = env::args().nth(2).expect("usage: equiv <program file>");
+    let (instructions, ip_register) = read_program();
+    let (edited, edited_ip_register) = read_program_file(&path);
+
+    let states = initial_states(&[0], EQUIVALENCE_RANDOM_STATES, 19);
+    let difference = find_difference(
+        elfcode_runner(&instructions, ip_register, EQUIVALENCE_CYCLES),
+        elfcode_runner(&edited, edited_ip_register, EQUIVALENCE_CYCLES),
+        &states,
+        &[ip_register, edited_ip_register],
+    );
+    match difference {
+        Some(d) => println!("{d}"),
+        None => println!("no difference in {} initial states", states.len()),
     }
+}
 
-    result
+// Runs the program from the file given after `run` with its ports reading from stdin, e.g.
+// `echo 1 2 3 | 19 run src/19_io_example.input` prints 6
+fn run_interactive() {
+    let path = env::args().nth(2).expect("usage: run <program file>");
+    let (program, ip_reg) = read_program_file(&path);
+    let mut vm = Vm::new(&program, ip_reg, &[0; REGISTERS_CNT]);
+    vm.attach(Device::from_stdin());
+
+    if let Err(e) = vm.run_checked(None) {
+        println!("{e}");
+    }
 }
 
-fn part_two() {
-    let (instructions, ip_register) = read_input();
+fn print_decompiled() {
+    let (instructions, ip_register) = read_program();
+    print!("{}", decompile(&instructions, ip_register));
+}
+
+const CYCLE_BUDGET: usize = 100_000_000;
+const PROFILE_CYCLES: usize = 10_000_000;
+const CHECKPOINT_INTERVAL: usize = 100_000;
+const EQUIVALENCE_CYCLES: usize = 10_000_000;
+const EQUIVALENCE_RANDOM_STATES: usize = 20;
+
+fn print_profile() {
+    let (instructions, ip_register) = read_program();
     let mut start_state = vec![0; REGISTERS_CNT];
     start_state[0] = 1;
-    let parameters = execute(&instructions, ip_register, &start_state, Some(50));
 
-    // Reverse-engineered what the program does and wrote a function that computes it faster ..
-    let n = parameters[4];
-    let result = sum_divisors(n as u64);
-    println!("{result}");
+    let profile = profile(
+        &instructions,
+        ip_register,
+        &start_state,
+        Some(PROFILE_CYCLES),
+    );
+    print!(
+        "{}",
+        annotated_listing(&instructions, ip_register, &profile)
+    );
+}
+
+// The program comes from the file given after `debug` since stdin is used for commands
+fn debug() {
+    let path = env::args().nth(2).expect("usage: debug <program file>");
+    let (program, ip_reg) = read_program_file(&path);
+    let vm = Vm::new(&program, ip_reg, &[0; REGISTERS_CNT]);
+
+    repl(&mut Debugger::new(vm, CHECKPOINT_INTERVAL), stdin().lock());
 }
 
 fn main() {
-    part_two();
+    match env::args().nth(1).as_deref() {
+        Some("decompile") => print_decompiled(),
+        Some("profile") => print_profile(),
+        Some("debug") => debug(),
+        Some("checked") => part_one_checked(),
+        Some("transpile") => print_transpiled(),
+        Some("equiv") => check_equivalence(),
+        Some("run") => run_interactive(),
+        Some("transpile-check") => transpile_check(),
+        _ => part_two(),
+    }
 }
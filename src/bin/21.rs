@@ -1,226 +1,156 @@
-use std::{
-    collections::{HashMap, HashSet},
-    io::stdin,
-    vec,
+use std::{collections::HashSet, env, io::stdin};
+
+use advent_2018::elfcode::{
+    compile::{Compiled, Machine},
+    debugger::{repl, Debugger},
+    decompile::decompile,
+    profile::{annotated_listing, profile},
+    read_program, read_program_file,
+    transpile::transpile,
+    Event, Instr,
+    OpCode::*,
+    Termination, Vm, Watch, REGISTERS_CNT,
 };
 
-use scanf::sscanf;
-use OpCode::*;
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum OpCode {
-    Addr,
-    Addi,
-    Mulr,
-    Muli,
-    Banr,
-    Bani,
-    Borr,
-    Bori,
-    Setr,
-    Seti,
-    Gtir,
-    Gtri,
-    Gtrr,
-    Eqir,
-    Eqri,
-    Eqrr,
+// The value an instruction reading register 0 compares it against; the program halts once they're equal
+fn compared_value(event: &Event) -> i64 {
+    let other = event.instr.reads().into_iter().find(|&r| r != 0).unwrap();
+    event.registers[other]
 }
 
-fn parse_opcode(s: &str) -> OpCode {
-    match s {
-        "addr" => Addr,
-        "addi" => Addi,
-        "mulr" => Mulr,
-        "muli" => Muli,
-        "banr" => Banr,
-        "bani" => Bani,
-        "borr" => Borr,
-        "bori" => Bori,
-        "setr" => Setr,
-        "seti" => Seti,
-        "gtir" => Gtir,
-        "gtri" => Gtri,
-        "gtrr" => Gtrr,
-        "eqir" => Eqir,
-        "eqri" => Eqri,
-        "eqrr" => Eqrr,
-        _ => todo!(),
+fn find_halting_check(program: &[Instr]) -> usize {
+    let checks = program
+        .iter()
+        .enumerate()
+        .filter(|(_, instr)| instr.reads().contains(&0))
+        .collect::<Vec<_>>();
+
+    match checks[..] {
+        [(ip, instr)] if instr.code == Eqrr && instr.op1 != instr.op2 => ip,
+        _ => panic!("expected exactly one eqrr comparing register 0, found {checks:?}"),
     }
 }
 
-#[derive(Debug, Clone, Copy)]
-struct Instr {
-    code: OpCode,
-    op1: i32,
-    op2: i32,
-    op3: i32,
-}
-fn do_instr(instr: Instr, rs: &mut Vec<i64>, current_cycle: usize, seen: &mut HashMap<i64, usize>) {
-    let Instr {
-        code,
-        op1: a,
-        op2: b,
-        op3: c,
-    } = instr;
-    match code {
-        Addr => rs[c as usize] = rs[a as usize] + rs[b as usize],
-        Addi => rs[c as usize] = rs[a as usize] + b as i64,
-        Mulr => rs[c as usize] = rs[a as usize] * rs[b as usize],
-        Muli => rs[c as usize] = rs[a as usize] * b as i64,
-        Banr => rs[c as usize] = rs[a as usize] & rs[b as usize],
-        Bani => rs[c as usize] = rs[a as usize] & b as i64,
-        Borr => rs[c as usize] = rs[a as usize] | rs[b as usize],
-        Bori => rs[c as usize] = rs[a as usize] | b as i64,
-        Setr => rs[c as usize] = rs[a as usize],
-        Seti => rs[c as usize] = a as i64,
-        Gtir => rs[c as usize] = if a as i64 > rs[b as usize] { 1 } else { 0 },
-        Gtri => rs[c as usize] = if rs[a as usize] > b as i64 { 1 } else { 0 },
-        Gtrr => {
-            rs[c as usize] = if rs[a as usize] > rs[b as usize] {
-                1
-            } else {
-                0
-            }
-        }
-        Eqir => rs[c as usize] = if a as i64 == rs[b as usize] { 1 } else { 0 },
-        Eqri => rs[c as usize] = if rs[a as usize] == b as i64 { 1 } else { 0 },
-        Eqrr => {
-            if a == 3 && b == 0 {
-                if !seen.contains_key(&rs[3]) {
-                    seen.insert(rs[3], current_cycle);
-                }
-            }
-            rs[c as usize] = if rs[a as usize] == rs[b as usize] {
-                1
-            } else {
-                0
+// Values register 0 is compared against, in the order the program checks them, up to the first repeat
+// (or `max_values`); after it the program cycles, so any value of register 0 not in the list never halts it
+fn halting_values(program: &[Instr], ip_reg: usize, max_values: usize) -> Vec<i64> {
+    let mut vm = Vm::new(program, ip_reg, &[0; REGISTERS_CNT]);
+    vm.accelerate();
+    vm.watch(Watch::Ip(find_halting_check(program)));
+
+    let mut seen = HashSet::new();
+    let mut result = Vec::new();
+    while result.len() < max_values && vm.step() {
+        if let Some(event) = vm.events.pop() {
+            let value = compared_value(&event);
+            if !seen.insert(value) {
+                break;
             }
+            result.push(value);
         }
     }
+
+    result
 }
 
-const REGISTERS_CNT: usize = 6;
-fn execute(
-    program: &Vec<Instr>,
-    ip_reg: usize,
-    start_state: &Vec<i64>,
-    instructions_limit: Option<usize>,
-) -> (Vec<i64>, usize, HashMap<i64, usize>) {
-    let mut registers = start_state.clone();
-    let mut ip = 0;
-
-    let mut cycles = 0;
-    let mut seen_for_reg_3 = HashMap::new();
-
-    loop {
-        if ip as usize >= program.len()
-            || (instructions_limit.is_some() && cycles >= instructions_limit.unwrap())
-        {
+// Same as `halting_values`, but running every instruction of the compiled program instead of
+// recognizing its loops
+fn halting_values_compiled(program: &[Instr], ip_reg: usize, max_values: usize) -> Vec<i64> {
+    let check = find_halting_check(program);
+    let other = program[check]
+        .reads()
+        .into_iter()
+        .find(|&r| r != 0)
+        .unwrap();
+    let compiled = Compiled::new(program, ip_reg);
+    let mut machine = Machine::new(&[0; REGISTERS_CNT]);
+
+    let mut seen = HashSet::new();
+    let mut result = Vec::new();
+    while result.len() < max_values && !compiled.run(&mut machine, Some(check), None) {
+        let value = machine.registers[other];
+        if !seen.insert(value) {
             break;
         }
-        let instr = program[ip as usize];
-        registers[ip_reg] = ip;
-
-        do_instr(instr, &mut registers, cycles, &mut seen_for_reg_3);
-        ip = registers[ip_reg];
-        ip += 1;
-
-        cycles += 1;
+        result.push(value);
     }
 
-    (registers, cycles, seen_for_reg_3)
+    result
 }
 
-fn read_program() -> (Vec<Instr>, usize) {
-    let mut ip_register = 0;
-    let mut instructions = Vec::new();
-    for line in stdin().lines().map(|l| l.unwrap()) {
-        let mut ip_reg = 0;
-        let (mut instr_type, mut op1, mut op2, mut op3) = (String::new(), 0, 0, 0);
-        if sscanf!(&line, "ip {}", ip_reg).is_ok() {
-            ip_register = ip_reg;
-        } else if sscanf!(&line, "{} {} {} {}", instr_type, op1, op2, op3).is_ok() {
-            let instr_type = parse_opcode(&instr_type);
-            instructions.push(Instr {
-                code: instr_type,
-                op1: op1,
-                op2: op2,
-                op3: op3,
-            });
-        }
+// The value halting the program soonest, then the one halting it last
+fn print_first_and_last(values: &[i64]) {
+    match (values.first(), values.last()) {
+        (Some(first), Some(last)) => println!("{first}\n{last}"),
+        _ => println!("the program halted before comparing register 0 to anything"),
     }
-
-    (instructions, ip_register as usize)
 }
 
-fn part_one() {
+// Whether the program halts for the value of register 0 given after `halts`
+fn check_halts() {
+    let r0 = env::args()
+        .nth(2)
+        .and_then(|v| v.parse::<i64>().ok())
+        .expect("usage: halts <register 0>");
     let (program, ip_reg) = read_program();
-
-    let mut state = vec![0; REGISTERS_CNT];
-    let (_, _, seen_values) = execute(&program, ip_reg, &state, Some(1000000));
-
-    let earliest_reg_3_value = seen_values
-        .iter()
-        .min_by_key(|(_, cycle)| **cycle)
-        .unwrap()
-        .0;
-
-    println!("{earliest_reg_3_value}");
+    let mut start_state = [0; REGISTERS_CNT];
+    start_state[0] = r0;
+
+    let mut vm = Vm::new(&program, ip_reg, &start_state);
+    vm.accelerate();
+    match vm.run_detecting_loops() {
+        Termination::Halted => println!("program halts after {} instructions", vm.cycles),
+        never_halts => println!("{never_halts}"),
+    }
 }
 
-// Generates the last value of register 3 which is unique; translated from the puzzle input and optimized some manual division; way faster, obviously
-fn reg_3_last_unique_value() -> i64 {
-    let (mut r3, mut r4) = (0i64, 0i64);
-    let mut last_r3 = r3;
-    let mut skip = false;
-
-    let m = 16777215;
+fn parts_one_and_two() {
+    let (program, ip_reg) = read_program();
+    print_first_and_last(&halting_values(&program, ip_reg, usize::MAX));
+}
 
-    let mut seen = HashSet::new();
+// Brute force without loop recognition, slow but independent of the idioms
+fn parts_one_and_two_compiled() {
+    let (program, ip_reg) = read_program();
+    print_first_and_last(&halting_values_compiled(&program, ip_reg, usize::MAX));
+}
 
-    loop {
-        if !skip {
-            r4 = r3 | 65536;
-            r3 = 7041048;
-        }
-        r3 += r4 & 255;
-        r3 &= m;
-        r3 *= 65899;
-        r3 &= m;
-
-        if r4 < 256 {
-            if seen.contains(&r3) {
-                return last_r3;
-            }
-            seen.insert(r3);
-            last_r3 = r3;
-            skip = false;
-        } else {
-            r4 /= 256;
-            skip = true;
-        }
-    }
+fn print_transpiled() {
+    let (program, ip_reg) = read_program();
+    print!("{}", transpile(&program, ip_reg));
 }
 
-fn part_two() {
-    let latest_reg_3_value = reg_3_last_unique_value();
+fn print_decompiled() {
+    let (program, ip_reg) = read_program();
+    print!("{}", decompile(&program, ip_reg));
+}
 
-    // Also works, but is a lot slower
-    // let (program, ip_reg) = read_program();
+const PROFILE_CYCLES: usize = 10_000_000;
+const CHECKPOINT_INTERVAL: usize = 100_000;
 
-    // let mut state = vec![0; REGISTERS_CNT];
-    // let (_, _, seen_values) = execute(&program, ip_reg, &state, Some(6_000_000_000));
+fn print_profile() {
+    let (program, ip_reg) = read_program();
+    let profile = profile(&program, ip_reg, &[0; REGISTERS_CNT], Some(PROFILE_CYCLES));
+    print!("{}", annotated_listing(&program, ip_reg, &profile));
+}
 
-    // let latest_reg_3_value = seen_values
-    //     .iter()
-    //     .max_by_key(|(_, cycle)| **cycle)
-    //     .unwrap()
-    //     .0;
+// The program comes from the file given after `debug` since stdin is used for commands
+fn debug() {
+    let path = env::args().nth(2).expect("usage: debug <program file>");
+    let (program, ip_reg) = read_program_file(&path);
+    let vm = Vm::new(&program, ip_reg, &[0; REGISTERS_CNT]);
 
-    println!("{latest_reg_3_value}");
+    repl(&mut Debugger::new(vm, CHECKPOINT_INTERVAL), stdin().lock());
 }
 
 fn main() {
-    part_two();
+    match env::args().nth(1).as_deref() {
+        Some("decompile") => print_decompiled(),
+        Some("transpile") => print_transpiled(),
+        Some("profile") => print_profile(),
+        Some("debug") => debug(),
+        Some("halts") => check_halts(),
+        Some("compiled") => parts_one_and_two_compiled(),
+        _ => parts_one_and_two(),
+    }
 }
@@ -0,0 +1,548 @@
+use super::{do_instr, Instr, OpCode::*, REGISTERS_CNT};
+
+// Pre-decoded instructions: the ip register is never stored while running, reads of it are
+// replaced by the (known) index of the instruction and writes to it become jumps
+#[derive(Debug, Clone, Copy)]
+enum Code {
+    AddRR(u8, u8, u8),
+    AddRI(u8, i64, u8),
+    MulRR(u8, u8, u8),
+    MulRI(u8, i64, u8),
+    AndRR(u8, u8, u8),
+    AndRI(u8, i64, u8),
+    OrRR(u8, u8, u8),
+    OrRI(u8, i64, u8),
+    SetR(u8, u8),
+    SetI(i64, u8),
+    GtIR(i64, u8, u8),
+    GtRI(u8, i64, u8),
+    GtRR(u8, u8, u8),
+    EqIR(i64, u8, u8),
+    EqRI(u8, i64, u8),
+    EqRR(u8, u8, u8),
+    Jump(i64),
+    // ip = register + offset
+    JumpReg(u8, i64),
+    // Any other write to the ip register, evaluated by the interpreter
+    JumpComputed,
+    // Registered opcodes, also left to the interpreter
+    Interpreted,
+    // A comparison into `c` followed by `addr c ip ip` at the next index: both are executed at
+    // once, skipping the instruction after the pair when the comparison holds
+    BranchGtRR(u8, u8, u8),
+    BranchGtRI(u8, i64, u8),
+    BranchGtIR(i64, u8, u8),
+    BranchEqRR(u8, u8, u8),
+    BranchEqRI(u8, i64, u8),
+    BranchEqIR(i64, u8, u8),
+    // Placed after the last instruction and on the instruction a run should stop at
+    Halt,
+}
+
+use Code::*;
+
+fn compile_instr(instr: Instr, ip: usize, ip_reg: usize) -> Code {
+    let (a, b, c) = (instr.op1 as u8, instr.op2 as u8, instr.op3 as u8);
+    let (imm_a, imm_b) = (instr.op1 as i64, instr.op2 as i64);
+    let (ip_value, ip_reg) = (ip as i64, ip_reg as u8);
+
+    if c == ip_reg {
+        return match instr.code {
+            Seti => Jump(imm_a + 1),
+            Addi if a == ip_reg => Jump(ip_value + imm_b + 1),
+            Muli if a == ip_reg => Jump(ip_value * imm_b + 1),
+            Mulr if a == ip_reg && b == ip_reg => Jump(ip_value * ip_value + 1),
+            Addr if a == ip_reg && b == ip_reg => Jump(2 * ip_value + 1),
+            Addr if a == ip_reg => JumpReg(b, ip_value + 1),
+            Addr if b == ip_reg => JumpReg(a, ip_value + 1),
+            Addi => JumpReg(a, imm_b + 1),
+            Setr if a == ip_reg => Jump(ip_value + 1),
+            Setr => JumpReg(a, 1),
+            _ => JumpComputed,
+        };
+    }
+
+    // Reads of the ip register are constants, the forms with an immediate take them
+    let (ra, rb) = (a == ip_reg, b == ip_reg);
+    match instr.code {
+        Addr if ra && rb => SetI(2 * ip_value, c),
+        Addr if ra => AddRI(b, ip_value, c),
+        Addr if rb => AddRI(a, ip_value, c),
+        Addr => AddRR(a, b, c),
+        Addi if ra => SetI(ip_value + imm_b, c),
+        Addi => AddRI(a, imm_b, c),
+        Mulr if ra && rb => SetI(ip_value * ip_value, c),
+        Mulr if ra => MulRI(b, ip_value, c),
+        Mulr if rb => MulRI(a, ip_value, c),
+        Mulr => MulRR(a, b, c),
+        Muli if ra => SetI(ip_value * imm_b, c),
+        Muli => MulRI(a, imm_b, c),
+        Banr if ra && rb => SetI(ip_value, c),
+        Banr if ra => AndRI(b, ip_value, c),
+        Banr if rb => AndRI(a, ip_value, c),
+        Banr => AndRR(a, b, c),
+        Bani if ra => SetI(ip_value & imm_b, c),
+        Bani => AndRI(a, imm_b, c),
+        Borr if ra && rb => SetI(ip_value, c),
+        Borr if ra => OrRI(b, ip_value, c),
+        Borr if rb => OrRI(a, ip_value, c),
+        Borr => OrRR(a, b, c),
+        Bori if ra => SetI(ip_value | imm_b, c),
+        Bori => OrRI(a, imm_b, c),
+        Setr if ra => SetI(ip_value, c),
+        Setr => SetR(a, c),
+        Seti => SetI(imm_a, c),
+        Gtir if rb => SetI((imm_a > ip_value) as i64, c),
+        Gtir => GtIR(imm_a, b, c),
+        Gtri if ra => SetI((ip_value > imm_b) as i64, c),
+        Gtri => GtRI(a, imm_b, c),
+        Gtrr if ra && rb => SetI(0, c),
+        Gtrr if ra => GtIR(ip_value, b, c),
+        Gtrr if rb => GtRI(a, ip_value, c),
+        Gtrr => GtRR(a, b, c),
+        Eqir if rb => SetI((imm_a == ip_value) as i64, c),
+        Eqir => EqIR(imm_a, b, c),
+        Eqri if ra => SetI((ip_value == imm_b) as i64, c),
+        Eqri => EqRI(a, imm_b, c),
+        Eqrr if ra && rb => SetI(1, c),
+        Eqrr if ra => EqIR(ip_value, b, c),
+        Eqrr if rb => EqRI(a, ip_value, c),
+        Eqrr => EqRR(a, b, c),
+        Custom(_) => Interpreted,
+    }
+}
+
+// The comparison at `code[ip]` can be fused with a following `ip += c`
+fn fuse(compare: Code, next: Code, ip: usize) -> Option<Code> {
+    let JumpReg(r, offset) = next else {
+        return None;
+    };
+    if offset != ip as i64 + 2 {
+        return None;
+    }
+    match compare {
+        GtRR(a, b, c) if c == r => Some(BranchGtRR(a, b, c)),
+        GtRI(a, b, c) if c == r => Some(BranchGtRI(a, b, c)),
+        GtIR(a, b, c) if c == r => Some(BranchGtIR(a, b, c)),
+        EqRR(a, b, c) if c == r => Some(BranchEqRR(a, b, c)),
+        EqRI(a, b, c) if c == r => Some(BranchEqRI(a, b, c)),
+        EqIR(a, b, c) if c == r => Some(BranchEqIR(a, b, c)),
+        _ => None,
+    }
+}
+
+// A code with the index of the step to go to after it, so that jumps known when compiling cost
+// nothing; the jumps that follow it are folded in
+#[derive(Debug, Clone, Copy)]
+struct Step {
+    code: Code,
+    next: usize,
+    // Instructions executed on the way to `next`, the code and the folded jumps
+    cycles: usize,
+    // Same for a fused comparison that holds
+    taken: usize,
+    taken_cycles: usize,
+}
+
+// Jump chains longer than this are left to run one jump per step, which also ends loops of jumps
+const MAX_FOLDED_JUMPS: usize = 4;
+const MAX_STEP_CYCLES: usize = 2 + MAX_FOLDED_JUMPS;
+
+// Still one dispatch per step, which makes it about 5-6 times as fast as `Vm` on day 21 rather
+// than an order of magnitude; `transpile` is the way to run at native speed
+pub struct Compiled {
+    program: Vec<Instr>,
+    code: Vec<Code>,
+    ip_reg: usize,
+    // Targets outside the program known when compiling, each gets its own `Halt` step after the
+    // program; the one after those is for jumps computed while running
+    exits: Vec<i64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Machine {
+    pub registers: [i64; REGISTERS_CNT],
+    pub ip: i64,
+    pub cycles: usize,
+}
+
+impl Machine {
+    pub fn new(start_state: &[i64]) -> Self {
+        Machine {
+            registers: start_state
+                .try_into()
+                .expect("should have one value per register"),
+            ip: 0,
+            cycles: 0,
+        }
+    }
+}
+
+// Registers are kept in an array of this size and indexed modulo it, which lets the compiler
+// drop the bounds checks; operands are validated against REGISTERS_CNT when compiling
+const SLOTS: usize = 8;
+
+impl Compiled {
+    // Panics on register operands out of range, use the checked VM for untrusted programs
+    pub fn new(program: &[Instr], ip_reg: usize) -> Self {
+        let mut operands = program
+            .iter()
+            .flat_map(|i| i.reads().into_iter().chain([i.writes()]));
+        assert!(ip_reg < REGISTERS_CNT && operands.all(|r| r < REGISTERS_CNT));
+
+        let code = program
+            .iter()
+            .enumerate()
+            .map(|(ip, &instr)| compile_instr(instr, ip, ip_reg))
+            .collect::<Vec<_>>();
+
+        // Falling off the end, possibly by skipping an instruction, and static jumps
+        let len = code.len() as i64;
+        let mut exits = vec![len, len + 1];
+        exits.extend(code.iter().filter_map(|&c| match c {
+            Jump(t) if !(0..len).contains(&t) => Some(t),
+            _ => None,
+        }));
+        exits.sort();
+        exits.dedup();
+
+        Compiled {
+            program: program.to_vec(),
+            code,
+            ip_reg,
+            exits,
+        }
+    }
+
+    // Where jumps computed while running and starting outside the program go
+    fn dynamic_exit(&self) -> usize {
+        self.code.len() + self.exits.len()
+    }
+
+    fn step_index(&self, target: i64) -> usize {
+        match usize::try_from(target) {
+            Ok(t) if t < self.code.len() => t,
+            _ => match self.exits.binary_search(&target) {
+                Ok(i) => self.code.len() + i,
+                Err(_) => self.dynamic_exit(),
+            },
+        }
+    }
+
+    // The steps of a run that stops before `stop_at`, followed by the `Halt` steps for the exits. Unless `fold` is false, comparisons are fused with the jump after them and
+    // jumps are folded into the step before them, but never across `stop_at`.
+    fn link(&self, stop_at: Option<usize>, fold: bool) -> Vec<Step> {
+        let len = self.code.len();
+        let reachable = |ip: usize| ip < len && Some(ip) != stop_at;
+        let follow = |mut target: i64, mut cycles: usize| {
+            for _ in 0..MAX_FOLDED_JUMPS {
+                match usize::try_from(target) {
+                    Ok(t) if fold && reachable(t) => match self.code[t] {
+                        Jump(next) => (target, cycles) = (next, cycles + 1),
+                        _ => break,
+                    },
+                    _ => break,
+                }
+            }
+            (target, cycles)
+        };
+
+        let mut steps = Vec::with_capacity(len + 1);
+        for (ip, &code) in self.code.iter().enumerate() {
+            let ip_value = ip as i64;
+            let fused = if fold && reachable(ip + 1) {
+                fuse(code, self.code[ip + 1], ip)
+            } else {
+                None
+            };
+            let (code, (next, cycles), (taken, taken_cycles)) = match (code, fused) {
+                (_, Some(branch)) => (branch, follow(ip_value + 2, 2), follow(ip_value + 3, 2)),
+                (Jump(target), _) => (code, follow(target, 1), (target, 1)),
+                _ => (code, follow(ip_value + 1, 1), (ip_value + 1, 1)),
+            };
+            steps.push(Step {
+                code,
+                next: self.step_index(next),
+                cycles,
+                taken: self.step_index(taken),
+                taken_cycles,
+            });
+        }
+        for exit in len..=self.dynamic_exit() {
+            steps.push(Step {
+                code: Halt,
+                next: exit,
+                cycles: 0,
+                taken: exit,
+                taken_cycles: 0,
+            });
+        }
+
+        steps
+    }
+
+    // Runs until the program halts, `stop_at` is about to execute or `instructions_limit` cycles
+    // have been executed in total; returns true if the program halted
+    pub fn run(
+        &self,
+        m: &mut Machine,
+        stop_at: Option<usize>,
+        instructions_limit: Option<usize>,
+    ) -> bool {
+        let len = self.code.len();
+        let stop_at = stop_at.filter(|&s| s < len);
+        // Folded steps while the limit is far enough, then one instruction at a time to stop on it
+        let mut folded = self.link(stop_at, true);
+        let mut single = self.link(stop_at, false);
+
+        let mut rs = [0; SLOTS];
+        rs[..REGISTERS_CNT].copy_from_slice(&m.registers);
+        let limit = instructions_limit.unwrap_or(usize::MAX);
+        let mut cycles = m.cycles;
+        // Where a jump computed while running left the program
+        let mut exit = m.ip;
+        let mut ip = if (m.ip as u64) < len as u64 {
+            m.ip as usize
+        } else {
+            self.dynamic_exit()
+        };
+
+        // Leaving `stop_at` is always possible, otherwise a second call would return immediately
+        if let Some(s) = stop_at {
+            if ip == s && cycles < limit {
+                ip = self.exec(single[ip], &mut rs, ip, &mut cycles, &mut exit);
+            }
+            folded[s].code = Halt;
+            single[s].code = Halt;
+        }
+        let folded_limit = limit.saturating_sub(MAX_STEP_CYCLES);
+        while cycles <= folded_limit && limit >= MAX_STEP_CYCLES {
+            match folded[ip] {
+                Step { code: Halt, .. } => break,
+                step => ip = self.exec(step, &mut rs, ip, &mut cycles, &mut exit),
+            }
+        }
+        while cycles < limit {
+            match single[ip] {
+                Step { code: Halt, .. } => break,
+                step => ip = self.exec(step, &mut rs, ip, &mut cycles, &mut exit),
+            }
+        }
+
+        let halted = ip >= len;
+        let next = if halted {
+            self.exits.get(ip - len).copied().unwrap_or(exit)
+        } else {
+            ip as i64
+        };
+        m.registers.copy_from_slice(&rs[..REGISTERS_CNT]);
+        if cycles > m.cycles {
+            // What the interpreter would have left there: the value the ip had before the increment
+            m.registers[self.ip_reg] = next - 1;
+        }
+        m.ip = next;
+        m.cycles = cycles;
+
+        halted
+    }
+
+    // Executes `step` at `ip`, returns the next index into the steps
+    #[inline(always)]
+    fn exec(
+        &self,
+        step: Step,
+        rs: &mut [i64; SLOTS],
+        ip: usize,
+        cycles: &mut usize,
+        exit: &mut i64,
+    ) -> usize {
+        macro_rules! r {
+            ($i:expr) => {
+                rs[$i as usize % SLOTS]
+            };
+        }
+        // Only for jumps computed while running, the others are already linked
+        let (len, dynamic_exit) = (self.code.len(), self.dynamic_exit());
+        let mut jump = |target: i64| {
+            if (target as u64) < len as u64 {
+                target as usize
+            } else {
+                *exit = target;
+                dynamic_exit
+            }
+        };
+
+        let mut taken = false;
+        match step.code {
+            AddRR(a, b, c) => r!(c) = r!(a) + r!(b),
+            AddRI(a, b, c) => r!(c) = r!(a) + b,
+            MulRR(a, b, c) => r!(c) = r!(a) * r!(b),
+            MulRI(a, b, c) => r!(c) = r!(a) * b,
+            AndRR(a, b, c) => r!(c) = r!(a) & r!(b),
+            AndRI(a, b, c) => r!(c) = r!(a) & b,
+            OrRR(a, b, c) => r!(c) = r!(a) | r!(b),
+            OrRI(a, b, c) => r!(c) = r!(a) | b,
+            SetR(a, c) => r!(c) = r!(a),
+            SetI(a, c) => r!(c) = a,
+            GtIR(a, b, c) => r!(c) = (a > r!(b)) as i64,
+            GtRI(a, b, c) => r!(c) = (r!(a) > b) as i64,
+            GtRR(a, b, c) => r!(c) = (r!(a) > r!(b)) as i64,
+            EqIR(a, b, c) => r!(c) = (a == r!(b)) as i64,
+            EqRI(a, b, c) => r!(c) = (r!(a) == b) as i64,
+            EqRR(a, b, c) => r!(c) = (r!(a) == r!(b)) as i64,
+            Jump(_) => {}
+            JumpReg(reg, offset) => {
+                *cycles += 1;
+                return jump(r!(reg) + offset);
+            }
+            JumpComputed => {
+                r!(self.ip_reg) = ip as i64;
+                do_instr(self.program[ip], &mut rs[..REGISTERS_CNT]);
+                *cycles += 1;
+                return jump(r!(self.ip_reg) + 1);
+            }
+            Interpreted => {
+                r!(self.ip_reg) = ip as i64;
+                do_instr(self.program[ip], &mut rs[..REGISTERS_CNT]);
+            }
+            BranchGtRR(a, b, c) => taken = compare(rs, c, r!(a) > r!(b)),
+            BranchGtRI(a, b, c) => taken = compare(rs, c, r!(a) > b),
+            BranchGtIR(a, b, c) => taken = compare(rs, c, a > r!(b)),
+            BranchEqRR(a, b, c) => taken = compare(rs, c, r!(a) == r!(b)),
+            BranchEqRI(a, b, c) => taken = compare(rs, c, r!(a) == b),
+            BranchEqIR(a, b, c) => taken = compare(rs, c, a == r!(b)),
+            Halt => unreachable!(),
+        }
+
+        if taken {
+            *cycles += step.taken_cycles;
+            step.taken
+        } else {
+            *cycles += step.cycles;
+            step.next
+        }
+    }
+}
+
+// The comparison half of a fused branch
+#[inline(always)]
+fn compare(rs: &mut [i64; SLOTS], c: u8, cond: bool) -> bool {
+    rs[c as usize % SLOTS] = cond as i64;
+    cond
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elfcode::{parse_program, read_program_file, Vm};
+
+    // Runs `compiled` in slices of `slice` cycles and checks it against the interpreter after each
+    fn check_against_vm(
+        program: &[Instr],
+        ip_reg: usize,
+        start: &[i64],
+        limit: usize,
+        slice: usize,
+    ) {
+        let compiled = Compiled::new(program, ip_reg);
+        let mut m = Machine::new(start);
+        let mut vm = Vm::new(program, ip_reg, start);
+        while m.cycles < limit {
+            let until = m.cycles + slice;
+            let halted = compiled.run(&mut m, None, Some(until));
+            vm.run(Some(vm.cycles + slice));
+            assert_eq!(
+                (&m.registers[..], m.ip, m.cycles, halted),
+                (&vm.registers[..], vm.ip, vm.cycles, vm.halted())
+            );
+            if halted {
+                break;
+            }
+        }
+    }
+
+    fn program(text: &str) -> (Vec<Instr>, usize) {
+        parse_program(text.lines().map(|l| l.trim().to_string()))
+    }
+
+    #[test]
+    fn matches_vm_on_day_19() {
+        let (program, ip_reg) = read_program_file("src/19.input");
+        check_against_vm(&program, ip_reg, &[0; REGISTERS_CNT], 1_000_000, 100_000);
+        check_against_vm(&program, ip_reg, &[1, 0, 0, 0, 0, 0], 1_000_000, 100_000);
+        check_against_vm(&program, ip_reg, &[0; REGISTERS_CNT], 1000, 1);
+        check_against_vm(&program, ip_reg, &[0; REGISTERS_CNT], 1000, 7);
+    }
+
+    #[test]
+    fn matches_vm_on_day_21() {
+        let (program, ip_reg) = read_program_file("src/21.input");
+        check_against_vm(&program, ip_reg, &[0; REGISTERS_CNT], 1_000_000, 100_000);
+        check_against_vm(&program, ip_reg, &[0; REGISTERS_CNT], 1000, 3);
+        check_against_vm(&program, ip_reg, &[10846352, 0, 0, 0, 0, 0], 10_000, 10_000);
+    }
+
+    #[test]
+    fn matches_vm_on_day_21_stops() {
+        let (program, ip_reg) = read_program_file("src/21.input");
+        let check = program.iter().position(|i| i.code == Eqrr).unwrap();
+        let compiled = Compiled::new(&program, ip_reg);
+        let mut m = Machine::new(&[0; REGISTERS_CNT]);
+        let mut vm = Vm::new(&program, ip_reg, &[0; REGISTERS_CNT]);
+        for _ in 0..10 {
+            assert!(!compiled.run(&mut m, Some(check), None));
+            while vm.step() && vm.ip as usize != check {}
+            assert_eq!(
+                (&m.registers[..], m.ip, m.cycles),
+                (&vm.registers[..], vm.ip, vm.cycles)
+            );
+        }
+    }
+
+    #[test]
+    fn matches_vm_when_ending_in_a_branch() {
+        let ends_in_branch = [
+            "#ip 5
+             seti 0 0 1
+             eqrr 1 2 1
+             addr 1 5 5",
+            "#ip 0
+             gtri 1 5 3
+             addr 3 0 0",
+            "#ip 2
+             addi 1 1 1
+             gtir 3 1 0
+             addr 0 2 2
+             seti 0 0 2",
+            "#ip 2
+             eqri 1 3 0
+             addr 0 2 2",
+        ];
+        for text in ends_in_branch {
+            let (program, ip_reg) = program(text);
+            for r in [0, 1, 6] {
+                let start = [r, r, r, 0, 0, 0];
+                check_against_vm(&program, ip_reg, &start, 100, 100);
+                check_against_vm(&program, ip_reg, &start, 100, 1);
+            }
+        }
+    }
+
+    #[test]
+    fn stays_halted() {
+        let (program, ip_reg) = program(
+            "#ip 5
+             seti 0 0 1
+             eqrr 1 2 1
+             addr 1 5 5",
+        );
+        let compiled = Compiled::new(&program, ip_reg);
+        for start in [[0; REGISTERS_CNT], [0, 0, 1, 0, 0, 0]] {
+            let mut m = Machine::new(&start);
+            assert!(compiled.run(&mut m, None, None));
+            let halted = m.clone();
+            assert!(compiled.run(&mut m, None, None));
+            assert_eq!(m, halted);
+        }
+    }
+}
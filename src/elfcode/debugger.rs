@@ -0,0 +1,225 @@
+use std::{collections::HashSet, io::BufRead};
+
+use super::{Snapshot, Vm};
+
+// Steps a VM while taking a snapshot every `interval` cycles, so that going back in time is a
+// restore of the closest earlier checkpoint followed by a replay up to the wanted cycle
+pub struct Debugger<'a> {
+    pub vm: Vm<'a>,
+    pub breakpoints: HashSet<usize>,
+    checkpoints: Vec<Snapshot>,
+    interval: usize,
+    // Registers set by hand as (cycle, register, value), replayed when the VM gets to the cycle
+    edits: Vec<(usize, usize, i64)>,
+}
+
+impl<'a> Debugger<'a> {
+    pub fn new(vm: Vm<'a>, interval: usize) -> Self {
+        let checkpoints = vec![vm.snapshot()];
+        Debugger {
+            vm,
+            breakpoints: HashSet::new(),
+            checkpoints,
+            interval: interval.max(1),
+            edits: Vec::new(),
+        }
+    }
+
+    // Steps the VM and applies the edits made at the cycle it gets to
+    fn replay_step(&mut self) -> bool {
+        if !self.vm.step() {
+            return false;
+        }
+        for &(_, reg, value) in self.edits.iter().filter(|e| e.0 == self.vm.cycles) {
+            self.vm.registers[reg] = value;
+        }
+
+        true
+    }
+
+    pub fn step(&mut self) -> bool {
+        if !self.replay_step() {
+            return false;
+        }
+        let last = self.checkpoints.last().unwrap().cycles;
+        if self.vm.cycles >= last + self.interval {
+            self.checkpoints.push(self.vm.snapshot());
+        }
+
+        true
+    }
+
+    // Runs until an instruction with a breakpoint is about to execute, the program halts or
+    // `instructions_limit` more cycles have passed; returns false if it halted
+    pub fn cont(&mut self, instructions_limit: Option<usize>) -> bool {
+        let start = self.vm.cycles;
+        loop {
+            if !self.step() {
+                return false;
+            }
+            if self.at_breakpoint()
+                || instructions_limit.is_some_and(|l| self.vm.cycles - start >= l)
+            {
+                return true;
+            }
+        }
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        self.vm.ip >= 0 && self.breakpoints.contains(&(self.vm.ip as usize))
+    }
+
+    fn checkpoint_before(&self, cycle: usize) -> usize {
+        self.checkpoints.partition_point(|c| c.cycles <= cycle) - 1
+    }
+
+    // Moves to the first instruction at or after `cycle`; later checkpoints stay valid since
+    // execution is deterministic
+    pub fn goto_cycle(&mut self, cycle: usize) {
+        let checkpoint = &self.checkpoints[self.checkpoint_before(cycle)];
+        if cycle < self.vm.cycles || checkpoint.cycles > self.vm.cycles {
+            self.vm.restore(checkpoint);
+        }
+        while self.vm.cycles < cycle && self.step() {}
+    }
+
+    // Returns false when already at the start of the program
+    pub fn reverse_step(&mut self) -> bool {
+        if self.vm.cycles == 0 {
+            return false;
+        }
+        let target = self.vm.cycles - 1;
+        let checkpoint = self.checkpoints[self.checkpoint_before(target)].clone();
+        self.vm.restore(&checkpoint);
+
+        // A loop run natively as an idiom is a single step, so this may stop before `target`
+        while self.vm.cycles < target {
+            let before = self.vm.snapshot();
+            if !self.replay_step() {
+                break;
+            }
+            if self.vm.cycles > target {
+                self.vm.restore(&before);
+                break;
+            }
+        }
+
+        true
+    }
+
+    // The edit becomes part of the history, going back and replaying through this cycle applies
+    // it again; edits and checkpoints after the current cycle assumed the old value, so they are
+    // dropped
+    pub fn set_register(&mut self, reg: usize, value: i64) {
+        self.vm.registers[reg] = value;
+        let cycles = self.vm.cycles;
+        self.edits.retain(|e| e.0 <= cycles);
+        self.edits.push((cycles, reg, value));
+        self.checkpoints.retain(|c| c.cycles < cycles);
+        self.checkpoints.push(self.vm.snapshot());
+    }
+
+    // Goes back to the latest earlier point where an instruction with a breakpoint was about to
+    // execute, or to the start of the program if there is none; returns false in the latter case
+    pub fn reverse_continue(&mut self) -> bool {
+        let current = self.vm.cycles;
+        let mut window = self.checkpoint_before(current.saturating_sub(1));
+        loop {
+            let window_start = self.checkpoints[window].clone();
+            let window_end = self
+                .checkpoints
+                .get(window + 1)
+                .map_or(current, |c| c.cycles.min(current));
+            self.vm.restore(&window_start);
+
+            let mut found = None;
+            while self.vm.cycles < window_end {
+                if self.at_breakpoint() {
+                    found = Some(self.vm.cycles);
+                }
+                if !self.step() {
+                    break;
+                }
+            }
+
+            if let Some(cycle) = found {
+                self.goto_cycle(cycle);
+                return true;
+            }
+            if window == 0 {
+                self.vm.restore(&self.checkpoints[0].clone());
+                return false;
+            }
+            window -= 1;
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        let instr = if self.vm.halted() {
+            "halted".to_string()
+        } else {
+            self.vm.program[self.vm.ip as usize].to_string()
+        };
+        format!(
+            "cycle {} ip {}: {:<20} {:?}",
+            self.vm.cycles, self.vm.ip, instr, self.vm.registers
+        )
+    }
+}
+
+const HELP: &str = "s [n]: step, c: continue, rs [n]: reverse step, rc: reverse continue, \
+b <ip>: toggle breakpoint, r <reg> <value>: set register, p: print state, q: quit";
+
+// Line based front end: reads commands until `q` or the end of the input, printing the VM state
+// after each of them
+pub fn repl(debugger: &mut Debugger, commands: impl BufRead) {
+    println!("{HELP}");
+    println!("{}", debugger.describe());
+    for line in commands.lines().map(|l| l.unwrap()) {
+        let parts = line.split_whitespace().collect::<Vec<_>>();
+        let arg = |i: usize| parts.get(i).and_then(|p| p.parse::<i64>().ok());
+        match parts.first().copied() {
+            Some("s") => {
+                for _ in 0..arg(1).unwrap_or(1) {
+                    if !debugger.step() {
+                        break;
+                    }
+                }
+            }
+            Some("c") => {
+                if !debugger.cont(None) {
+                    println!("program halted");
+                }
+            }
+            Some("rs") => {
+                for _ in 0..arg(1).unwrap_or(1) {
+                    if !debugger.reverse_step() {
+                        break;
+                    }
+                }
+            }
+            Some("rc") => {
+                if !debugger.reverse_continue() {
+                    println!("no earlier breakpoint hit, back at the start");
+                }
+            }
+            Some("b") => match arg(1) {
+                Some(ip) if !debugger.breakpoints.remove(&(ip as usize)) => {
+                    debugger.breakpoints.insert(ip as usize);
+                }
+                Some(_) => {}
+                None => println!("breakpoints: {:?}", debugger.breakpoints),
+            },
+            Some("r") => match (arg(1), arg(2)) {
+                (Some(r), Some(value)) if (r as usize) < debugger.vm.registers.len() => {
+                    debugger.set_register(r as usize, value);
+                }
+                _ => println!("usage: r <reg> <value>"),
+            },
+            Some("p") | None => {}
+            Some("q") => break,
+            Some(_) => println!("{HELP}"),
+        }
+        println!("{}", debugger.describe());
+    }
+}
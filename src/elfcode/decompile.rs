@@ -0,0 +1,464 @@
+use std::{collections::HashSet, fmt};
+
+use super::{isa::Operand, Instr, OpCode, OpCode::*};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Mul,
+    And,
+    Or,
+    Gt,
+    Le,
+    Eq,
+    Ne,
+}
+
+impl BinOp {
+    fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Add => "+",
+            BinOp::Mul => "*",
+            BinOp::And => "&",
+            BinOp::Or => "|",
+            BinOp::Gt => ">",
+            BinOp::Le => "<=",
+            BinOp::Eq => "==",
+            BinOp::Ne => "!=",
+        }
+    }
+
+    fn precedence(self) -> u8 {
+        match self {
+            BinOp::Or => 1,
+            BinOp::And => 2,
+            BinOp::Gt | BinOp::Le | BinOp::Eq | BinOp::Ne => 3,
+            BinOp::Add => 4,
+            BinOp::Mul => 5,
+        }
+    }
+
+    fn is_comparison(self) -> bool {
+        self.precedence() == 3
+    }
+
+    fn eval(self, a: i64, b: i64) -> i64 {
+        match self {
+            BinOp::Add => a + b,
+            BinOp::Mul => a * b,
+            BinOp::And => a & b,
+            BinOp::Or => a | b,
+            BinOp::Gt => (a > b) as i64,
+            BinOp::Le => (a <= b) as i64,
+            BinOp::Eq => (a == b) as i64,
+            BinOp::Ne => (a != b) as i64,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Const(i64),
+    Reg(usize),
+    Bin(BinOp, Box<Expr>, Box<Expr>),
+    // A registered opcode applied to its non-ignored operands
+    Call(OpCode, Vec<Expr>),
+}
+
+impl Expr {
+    // Builds `l op r`, folding constants and keeping constant offsets on the right of sums
+    fn bin(op: BinOp, l: Expr, r: Expr) -> Expr {
+        match (op, l, r) {
+            (op, Expr::Const(a), Expr::Const(b)) => Expr::Const(op.eval(a, b)),
+            (BinOp::Add, e, Expr::Const(0)) | (BinOp::Add, Expr::Const(0), e) => e,
+            (BinOp::Mul, e, Expr::Const(1)) | (BinOp::Mul, Expr::Const(1), e) => e,
+            (BinOp::Add, Expr::Const(c), e) => Expr::bin(BinOp::Add, e, Expr::Const(c)),
+            (BinOp::Add, Expr::Bin(BinOp::Add, e, c1), Expr::Const(c2))
+                if matches!(*c1, Expr::Const(_)) =>
+            {
+                Expr::bin(BinOp::Add, *e, Expr::bin(BinOp::Add, *c1, Expr::Const(c2)))
+            }
+            (op, l, r) => Expr::Bin(op, Box::new(l), Box::new(r)),
+        }
+    }
+
+    fn negate(self) -> Expr {
+        match self {
+            Expr::Bin(BinOp::Eq, e, zero) if *zero == Expr::Const(0) && e.is_boolean() => *e,
+            Expr::Bin(BinOp::Gt, l, r) => Expr::Bin(BinOp::Le, l, r),
+            Expr::Bin(BinOp::Le, l, r) => Expr::Bin(BinOp::Gt, l, r),
+            Expr::Bin(BinOp::Eq, l, r) => Expr::Bin(BinOp::Ne, l, r),
+            Expr::Bin(BinOp::Ne, l, r) => Expr::Bin(BinOp::Eq, l, r),
+            e => Expr::Bin(BinOp::Eq, Box::new(e), Box::new(Expr::Const(0))),
+        }
+    }
+
+    // Registers used as branch conditions are only ever the result of a comparison, see `lift`
+    fn is_boolean(&self) -> bool {
+        match self {
+            Expr::Reg(_) => true,
+            Expr::Bin(op, _, _) => op.is_comparison(),
+            Expr::Const(c) => *c == 0 || *c == 1,
+            Expr::Call(_, _) => false,
+        }
+    }
+
+    fn mentions(&self, reg: usize) -> bool {
+        match self {
+            Expr::Const(_) => false,
+            Expr::Reg(r) => *r == reg,
+            Expr::Bin(_, l, r) => l.mentions(reg) || r.mentions(reg),
+            Expr::Call(_, args) => args.iter().any(|a| a.mentions(reg)),
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            Expr::Bin(op, _, _) => op.precedence(),
+            _ => u8::MAX,
+        }
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Expr::Const(c) => write!(f, "{c}"),
+            Expr::Reg(r) => write!(f, "r{r}"),
+            Expr::Call(code, args) => {
+                let args = args.iter().map(|a| a.to_string()).collect::<Vec<_>>();
+                write!(f, "{}({})", code.mnemonic(), args.join(", "))
+            }
+            Expr::Bin(op, l, r) => {
+                let prec = op.precedence();
+                if l.precedence() < prec {
+                    write!(f, "({l})")?;
+                } else {
+                    write!(f, "{l}")?;
+                }
+                write!(f, " {} ", op.symbol())?;
+                if r.precedence() < prec || (r.precedence() == prec && op.is_comparison()) {
+                    write!(f, "({r})")
+                } else {
+                    write!(f, "{r}")
+                }
+            }
+        }
+    }
+}
+
+// One instruction after the ip register has been resolved; jump targets equal to the
+// program length mean the program halts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Op {
+    Assign(usize, Expr),
+    Goto(usize),
+    Branch(Expr, usize),
+    ComputedGoto(Expr),
+    Nop,
+}
+
+fn lift_instr(instr: Instr, ip: usize, ip_reg: usize, len: usize) -> Op {
+    let reg = |r: i32| {
+        if r as usize == ip_reg {
+            Expr::Const(ip as i64)
+        } else {
+            Expr::Reg(r as usize)
+        }
+    };
+    let (a, b) = (instr.op1, instr.op2);
+    let (imm_a, imm_b) = (Expr::Const(a as i64), Expr::Const(b as i64));
+    let expr = match instr.code {
+        Addr => Expr::bin(BinOp::Add, reg(a), reg(b)),
+        Addi => Expr::bin(BinOp::Add, reg(a), imm_b),
+        Mulr => Expr::bin(BinOp::Mul, reg(a), reg(b)),
+        Muli => Expr::bin(BinOp::Mul, reg(a), imm_b),
+        Banr => Expr::bin(BinOp::And, reg(a), reg(b)),
+        Bani => Expr::bin(BinOp::And, reg(a), imm_b),
+        Borr => Expr::bin(BinOp::Or, reg(a), reg(b)),
+        Bori => Expr::bin(BinOp::Or, reg(a), imm_b),
+        Setr => reg(a),
+        Seti => imm_a,
+        Gtir => Expr::bin(BinOp::Gt, imm_a, reg(b)),
+        Gtri => Expr::bin(BinOp::Gt, reg(a), imm_b),
+        Gtrr => Expr::bin(BinOp::Gt, reg(a), reg(b)),
+        Eqir => Expr::bin(BinOp::Eq, imm_a, reg(b)),
+        Eqri => Expr::bin(BinOp::Eq, reg(a), imm_b),
+        Eqrr => Expr::bin(BinOp::Eq, reg(a), reg(b)),
+        Custom(_) => {
+            let [kind_a, kind_b] = instr.code.operands();
+            let args = [(kind_a, a), (kind_b, b)]
+                .into_iter()
+                .filter_map(|(kind, v)| match kind {
+                    Operand::Register => Some(reg(v)),
+                    Operand::Immediate => Some(Expr::Const(v as i64)),
+                    Operand::Ignored => None,
+                })
+                .collect();
+            Expr::Call(instr.code, args)
+        }
+    };
+
+    if instr.op3 as usize != ip_reg {
+        return Op::Assign(instr.op3 as usize, expr);
+    }
+    match Expr::bin(BinOp::Add, expr, Expr::Const(1)) {
+        Expr::Const(target) => Op::Goto(if target < 0 {
+            len
+        } else {
+            len.min(target as usize)
+        }),
+        target => Op::ComputedGoto(target),
+    }
+}
+
+fn static_targets(op: &Op) -> Option<usize> {
+    match op {
+        Op::Goto(t) | Op::Branch(_, t) => Some(*t),
+        _ => None,
+    }
+}
+
+// Lifts every instruction and recognizes the two jump idioms ElfCode has instead of
+// conditional branches: `ip += <comparison result>` and skipping over an unconditional goto
+pub fn lift(program: &[Instr], ip_reg: usize) -> Vec<Op> {
+    let len = program.len();
+    let mut ops = program
+        .iter()
+        .enumerate()
+        .map(|(ip, &instr)| lift_instr(instr, ip, ip_reg, len))
+        .collect::<Vec<_>>();
+
+    let is_target = |ops: &Vec<Op>, idx: usize, except: usize| {
+        ops.iter()
+            .enumerate()
+            .any(|(i, op)| i != except && static_targets(op) == Some(idx))
+    };
+
+    for i in 1..len {
+        let Op::ComputedGoto(Expr::Bin(BinOp::Add, r, offset)) = &ops[i] else {
+            continue;
+        };
+        let (Expr::Reg(r), Expr::Const(offset)) = (r.as_ref(), offset.as_ref()) else {
+            continue;
+        };
+        if *offset != i as i64 + 1 || is_target(&ops, i, i) {
+            continue;
+        }
+        if let Op::Assign(dest, cmp @ Expr::Bin(op, _, _)) = &ops[i - 1] {
+            if dest == r && op.is_comparison() {
+                let cond = if cmp.mentions(*r) {
+                    Expr::Reg(*r)
+                } else {
+                    cmp.clone()
+                };
+                ops[i] = Op::Branch(cond, (i + 2).min(len));
+            }
+        }
+    }
+
+    for i in 0..len.saturating_sub(1) {
+        if let (Op::Branch(cond, skip), Op::Goto(target)) = (&ops[i], &ops[i + 1]) {
+            if *skip == i + 2 && !is_target(&ops, i + 1, i) {
+                ops[i] = Op::Branch(cond.clone().negate(), *target);
+                ops[i + 1] = Op::Nop;
+            }
+        }
+    }
+
+    ops
+}
+
+#[derive(Debug)]
+enum Node {
+    Stmt(usize),
+    If(usize, Expr, Vec<Node>),
+    Loop(usize, usize, Vec<Node>),
+    DoWhile(usize, usize, Vec<Node>, Expr),
+}
+
+pub(crate) struct Decompiler<'a> {
+    ops: &'a [Op],
+    // (from, to) for every jump, computed gotos conservatively target everything after their base
+    edges: Vec<(usize, usize)>,
+}
+
+impl<'a> Decompiler<'a> {
+    pub(crate) fn new(ops: &'a [Op]) -> Self {
+        let mut edges = Vec::new();
+        for (i, op) in ops.iter().enumerate() {
+            match op {
+                Op::Goto(t) | Op::Branch(_, t) => edges.push((i, *t)),
+                Op::ComputedGoto(e) => {
+                    for t in computed_targets(e, ops.len()) {
+                        edges.push((i, t));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Decompiler { ops, edges }
+    }
+
+    // No jump from outside of [from, to) lands on an instruction in (from, to) or, with
+    // `include_start`, on `from` itself
+    pub(crate) fn is_closed(&self, from: usize, to: usize, include_start: bool) -> bool {
+        !self.edges.iter().any(|&(src, dst)| {
+            let outside = src < from || src >= to;
+            let inside = if include_start {
+                from <= dst && dst < to
+            } else {
+                from < dst && dst < to
+            };
+            outside && inside
+        })
+    }
+
+    fn structure(&self, start: usize, end: usize) -> Vec<Node> {
+        let mut result = Vec::new();
+        let mut i = start;
+        while i < end {
+            let back_jump = (i..end).rev().find(|&j| {
+                static_targets(&self.ops[j]) == Some(i) && self.is_closed(i, j + 1, false)
+            });
+            if let Some(j) = back_jump {
+                let body = self.structure(i, j);
+                result.push(match &self.ops[j] {
+                    Op::Branch(cond, _) => Node::DoWhile(i, j, body, cond.clone()),
+                    _ => Node::Loop(i, j, body),
+                });
+                i = j + 1;
+                continue;
+            }
+
+            match &self.ops[i] {
+                Op::Branch(cond, t)
+                    if *t > i + 1 && *t <= end && self.is_closed(i + 1, *t, true) =>
+                {
+                    let body = self.structure(i + 1, *t);
+                    result.push(Node::If(i, cond.clone().negate(), body));
+                    i = *t;
+                }
+                _ => {
+                    result.push(Node::Stmt(i));
+                    i += 1;
+                }
+            }
+        }
+
+        result
+    }
+}
+
+fn computed_targets(e: &Expr, len: usize) -> Vec<usize> {
+    match e {
+        Expr::Bin(BinOp::Add, _, base) => match base.as_ref() {
+            Expr::Const(c) if *c >= 0 => (*c as usize..len).collect(),
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+struct Printer<'a> {
+    ops: &'a [Op],
+    lines: Vec<(Option<usize>, usize, String)>,
+    labels: HashSet<usize>,
+}
+
+impl Printer<'_> {
+    fn line(&mut self, idx: Option<usize>, depth: usize, text: String) {
+        self.lines.push((idx, depth, text));
+    }
+
+    fn jump(&mut self, target: usize, exit: Option<usize>) -> String {
+        if target >= self.ops.len() {
+            "halt".to_string()
+        } else if Some(target) == exit {
+            "break".to_string()
+        } else {
+            self.labels.insert(target);
+            format!("goto L{target}")
+        }
+    }
+
+    fn print(&mut self, nodes: &[Node], depth: usize, exit: Option<usize>) {
+        for node in nodes {
+            match node {
+                Node::Stmt(i) => {
+                    let text = match &self.ops[*i] {
+                        Op::Assign(r, e) => assignment(*r, e),
+                        Op::Goto(t) => self.jump(*t, exit),
+                        Op::Branch(cond, t) => format!("if {cond} {}", self.jump(*t, exit)),
+                        Op::ComputedGoto(e) => {
+                            self.labels.extend(computed_targets(e, self.ops.len()));
+                            format!("goto L[{e}]")
+                        }
+                        Op::Nop => String::new(),
+                    };
+                    self.line(Some(*i), depth, text);
+                }
+                Node::If(i, cond, body) => {
+                    self.line(Some(*i), depth, format!("if {cond} {{"));
+                    self.print(body, depth + 1, exit);
+                    self.line(None, depth, "}".to_string());
+                }
+                Node::Loop(i, j, body) => {
+                    self.line(Some(*i), depth, "loop {".to_string());
+                    self.print(body, depth + 1, Some(j + 1));
+                    self.line(Some(*j), depth, "}".to_string());
+                }
+                Node::DoWhile(i, j, body, cond) => {
+                    self.line(Some(*i), depth, "do {".to_string());
+                    self.print(body, depth + 1, Some(j + 1));
+                    self.line(Some(*j), depth, format!("}} while {cond}"));
+                }
+            }
+        }
+    }
+}
+
+fn assignment(r: usize, e: &Expr) -> String {
+    if let Expr::Bin(op @ (BinOp::Add | BinOp::Mul | BinOp::And | BinOp::Or), l, rhs) = e {
+        if **l == Expr::Reg(r) {
+            return format!("r{r} {}= {rhs}", op.symbol());
+        }
+        if **rhs == Expr::Reg(r) {
+            return format!("r{r} {}= {l}", op.symbol());
+        }
+    }
+    format!("r{r} = {e}")
+}
+
+// Turns a program into structured pseudo-code: the ip register is resolved into jumps,
+// backward jumps become `loop`/`do .. while` and forward conditional jumps become `if`s.
+// Jumps that don't fit that shape are kept as `goto` to labels named after instruction indices.
+pub fn decompile(program: &[Instr], ip_reg: usize) -> String {
+    let ops = lift(program, ip_reg);
+    let decompiler = Decompiler::new(&ops);
+    let nodes = decompiler.structure(0, ops.len());
+
+    let mut printer = Printer {
+        ops: &ops,
+        lines: Vec::new(),
+        labels: HashSet::new(),
+    };
+    printer.print(&nodes, 1, None);
+
+    let mut result = String::new();
+    let mut labelled = HashSet::new();
+    for (idx, depth, text) in &printer.lines {
+        if let Some(idx) = idx {
+            if printer.labels.contains(idx) && labelled.insert(*idx) {
+                result.push_str(&format!("L{idx}:\n"));
+            }
+        }
+        if text.is_empty() {
+            continue;
+        }
+        result.push_str(&format!("{}{text}\n", "    ".repeat(*depth)));
+    }
+
+    result
+}
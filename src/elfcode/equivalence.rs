@@ -0,0 +1,122 @@
+use std::fmt;
+
+use super::{rng::Rng, Instr, Vm, VmError, REGISTERS_CNT};
+
+// How a run from some initial state ended
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Outcome {
+    Halted(Vec<i64>),
+    Faulted(String),
+    // The cycle budget ran out first
+    Timeout,
+}
+
+impl Outcome {
+    // Two faults agree whatever they are, and so do two timeouts since nothing is known about
+    // the final states
+    fn agrees(&self, other: &Outcome, ignored: &[usize]) -> bool {
+        match (self, other) {
+            (Outcome::Halted(l), Outcome::Halted(r)) => {
+                l.len() == r.len() && (0..l.len()).all(|i| ignored.contains(&i) || l[i] == r[i])
+            }
+            (Outcome::Faulted(_), Outcome::Faulted(_)) | (Outcome::Timeout, Outcome::Timeout) => {
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::Halted(registers) => write!(f, "halted with {registers:?}"),
+            Outcome::Faulted(e) => write!(f, "{e}"),
+            Outcome::Timeout => write!(f, "still running"),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Difference {
+    pub start: Vec<i64>,
+    pub left: Outcome,
+    pub right: Outcome,
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "from {:?}: left {}, right {}",
+            self.start, self.left, self.right
+        )
+    }
+}
+
+// Runs a program in the checked VM, so that random states can't make it panic
+pub fn elfcode_runner(
+    program: &[Instr],
+    ip_reg: usize,
+    cycle_budget: usize,
+) -> impl Fn(&[i64]) -> Outcome + '_ {
+    move |start_state| {
+        let mut vm = Vm::new(program, ip_reg, start_state);
+        match vm.run_checked(Some(cycle_budget)) {
+            Ok(()) => Outcome::Halted(vm.registers),
+            Err(VmError::CycleBudgetExhausted { .. }) => Outcome::Timeout,
+            Err(e) => Outcome::Faulted(e.to_string()),
+        }
+    }
+}
+
+const EDGE_VALUES: [i64; 4] = [0, 1, 2, -1];
+const RANDOM_RANGE: i64 = 1000;
+
+// Every combination of edge values for the registers in `varied`, followed by `random_count`
+// states with those registers in -RANDOM_RANGE..=RANDOM_RANGE; the other registers are 0
+pub fn initial_states(varied: &[usize], random_count: usize, seed: u64) -> Vec<Vec<i64>> {
+    let mut result = vec![vec![0; REGISTERS_CNT]];
+    for &r in varied {
+        result = result
+            .into_iter()
+            .flat_map(|state| {
+                EDGE_VALUES.map(|v| {
+                    let mut state = state.clone();
+                    state[r] = v;
+                    state
+                })
+            })
+            .collect();
+    }
+
+    let mut rng = Rng::new(seed);
+    for _ in 0..random_count {
+        let mut state = vec![0; REGISTERS_CNT];
+        for &r in varied {
+            state[r] = rng.within(RANDOM_RANGE);
+        }
+        result.push(state);
+    }
+
+    result
+}
+
+// Runs both sides from every state in order and returns the first one they disagree on,
+// registers in `ignored` (typically the ip registers) left out of the comparison. Either side
+// can be a Rust closure standing in for a hand translation.
+pub fn find_difference(
+    left: impl Fn(&[i64]) -> Outcome,
+    right: impl Fn(&[i64]) -> Outcome,
+    states: &[Vec<i64>],
+    ignored: &[usize],
+) -> Option<Difference> {
+    states.iter().find_map(|start| {
+        let (l, r) = (left(start), right(start));
+        (!l.agrees(&r, ignored)).then(|| Difference {
+            start: start.clone(),
+            left: l,
+            right: r,
+        })
+    })
+}
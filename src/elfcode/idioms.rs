@@ -0,0 +1,341 @@
+use std::collections::HashSet;
+
+use super::{
+    decompile::{lift, BinOp, Decompiler, Expr, Op},
+    Instr,
+};
+
+// Loops whose effect on the registers can be computed directly; registers are named as in the
+// pseudo-code each variant documents
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Idiom {
+    // do { tmp = a * b == n; if tmp { acc += a } b += 1; tmp = b > n } while b <= n
+    DivisorSum {
+        a: usize,
+        b: usize,
+        n: usize,
+        acc: usize,
+        tmp: usize,
+        exit: usize,
+    },
+    // do { b = start; <DivisorSum> a += 1; tmp = a > n } while a <= n, summing the divisors of n
+    // from a on in O(sqrt(n))
+    DivisorSumOverRange {
+        a: usize,
+        b: usize,
+        n: usize,
+        acc: usize,
+        tmp: usize,
+        start: i64,
+        exit: usize,
+    },
+    // loop { tmp = (counter + offset) * factor > n; if tmp { break } counter += 1 }
+    CountUntilProductExceeds {
+        counter: usize,
+        offset: i64,
+        factor: i64,
+        n: usize,
+        tmp: usize,
+        exit: usize,
+    },
+}
+
+impl Idiom {
+    // Applies the whole loop to the registers, returns the ip it exits to and how many
+    // instructions the loop would have executed
+    pub fn apply(&self, rs: &mut [i64]) -> (i64, usize) {
+        match *self {
+            Idiom::DivisorSum {
+                a,
+                b,
+                n,
+                acc,
+                tmp,
+                exit,
+            } => {
+                let (first, last) = (rs[b], rs[b].max(rs[n]));
+                if rs[a] != 0 && rs[n] % rs[a] == 0 && (first..=last).contains(&(rs[n] / rs[a])) {
+                    rs[acc] += rs[a];
+                }
+                rs[b] = last + 1;
+                rs[tmp] = 1;
+
+                (exit as i64, (last - first + 1) as usize * 8 - 1)
+            }
+            Idiom::DivisorSumOverRange {
+                a,
+                b,
+                n,
+                acc,
+                tmp,
+                start,
+                exit,
+            } => {
+                let (first, last) = (rs[a], rs[a].max(rs[n]));
+                let inner_last = start.max(rs[n]);
+                rs[acc] += divisors_in_range(rs[n], first, last, start, inner_last);
+                rs[a] = last + 1;
+                rs[b] = inner_last + 1;
+                rs[tmp] = 1;
+
+                let iterations = (last - first + 1) as usize;
+                let inner_cycles = (inner_last - start + 1) as usize * 8 - 1;
+                (exit as i64, iterations * (inner_cycles + 5) - 1)
+            }
+            Idiom::CountUntilProductExceeds {
+                counter,
+                offset,
+                factor,
+                n,
+                tmp,
+                exit,
+            } => {
+                let first = rs[counter];
+                let last = first.max(rs[n].div_euclid(factor) - offset + 1);
+                rs[counter] = last;
+                rs[tmp] = 1;
+
+                (exit as i64, (last - first) as usize * 7 + 5)
+            }
+        }
+    }
+}
+
+// Sum of the d in first..=last with d * q == n for some q in q_first..=q_last
+fn divisors_in_range(n: i64, first: i64, last: i64, q_first: i64, q_last: i64) -> i64 {
+    let wanted = |d: i64| {
+        (first..=last).contains(&d) && d != 0 && n % d == 0 && (q_first..=q_last).contains(&(n / d))
+    };
+    if n < 1 || first < 1 {
+        // Not the usual shape, every candidate is checked
+        return (first..=last).filter(|&d| wanted(d)).sum();
+    }
+
+    let mut sum = 0;
+    let mut small = 1;
+    while small * small <= n {
+        if n % small == 0 {
+            let large = n / small;
+            sum += [small, large]
+                .into_iter()
+                .take(if small == large { 1 } else { 2 })
+                .filter(|&d| wanted(d))
+                .sum::<i64>();
+        }
+        small += 1;
+    }
+
+    sum
+}
+
+fn reg(e: &Expr) -> Option<usize> {
+    match e {
+        Expr::Reg(r) => Some(*r),
+        _ => None,
+    }
+}
+
+fn constant(e: &Expr) -> Option<i64> {
+    match e {
+        Expr::Const(c) => Some(*c),
+        _ => None,
+    }
+}
+
+fn bin(e: &Expr, op: BinOp) -> Option<(&Expr, &Expr)> {
+    match e {
+        Expr::Bin(o, l, r) if *o == op => Some((l, r)),
+        _ => None,
+    }
+}
+
+// Operands of a commutative `op` as (the one equal to `known`, the other)
+fn commuted(e: &Expr, op: BinOp, known: &Expr) -> Option<Expr> {
+    let (l, r) = bin(e, op)?;
+    if l == known {
+        Some(r.clone())
+    } else if r == known {
+        Some(l.clone())
+    } else {
+        None
+    }
+}
+
+fn assign(op: &Op) -> Option<(usize, &Expr)> {
+    match op {
+        Op::Assign(r, e) => Some((*r, e)),
+        _ => None,
+    }
+}
+
+fn increment(op: &Op, by: i64) -> Option<usize> {
+    let (r, e) = assign(op)?;
+    let (l, c) = bin(e, BinOp::Add)?;
+    (reg(l) == Some(r) && constant(c) == Some(by)).then_some(r)
+}
+
+fn branch_if_zero(op: &Op, tmp: usize, target: usize) -> Option<()> {
+    match op {
+        Op::Branch(cond, t) if *t == target => {
+            let (l, r) = bin(cond, BinOp::Eq)?;
+            (reg(l) == Some(tmp) && constant(r) == Some(0)).then_some(())
+        }
+        _ => None,
+    }
+}
+
+fn distinct(regs: &[usize]) -> bool {
+    regs.iter().collect::<HashSet<_>>().len() == regs.len()
+}
+
+fn divisor_sum(ops: &[Op], h: usize) -> Option<Idiom> {
+    let body = ops.get(h..h + 9)?;
+    let b = increment(&body[5], 1)?;
+
+    let (tmp, product) = assign(&body[0])?;
+    let a = reg(&commuted(product, BinOp::Mul, &Expr::Reg(b))?)?;
+
+    let (eq_dest, eq) = assign(&body[1])?;
+    let n = reg(&commuted(eq, BinOp::Eq, &Expr::Reg(tmp))?)?;
+
+    branch_if_zero(&body[2], tmp, h + 5)?;
+    (body[3] == Op::Nop).then_some(())?;
+    let (acc, sum) = assign(&body[4])?;
+    (commuted(sum, BinOp::Add, &Expr::Reg(acc))? == Expr::Reg(a)).then_some(())?;
+
+    let (gt_dest, gt) = assign(&body[6])?;
+    let exit_cond = Expr::Bin(BinOp::Le, Box::new(Expr::Reg(b)), Box::new(Expr::Reg(n)));
+    (bin(gt, BinOp::Gt)? == (&Expr::Reg(b), &Expr::Reg(n))).then_some(())?;
+    (body[7] == Op::Branch(exit_cond, h) && body[8] == Op::Nop).then_some(())?;
+
+    (eq_dest == tmp && gt_dest == tmp && distinct(&[a, b, n, acc, tmp])).then_some(
+        Idiom::DivisorSum {
+            a,
+            b,
+            n,
+            acc,
+            tmp,
+            exit: h + 9,
+        },
+    )
+}
+
+fn divisor_sum_over_range(ops: &[Op], h: usize) -> Option<Idiom> {
+    let body = ops.get(h..h + 14)?;
+    let Idiom::DivisorSum {
+        a, b, n, acc, tmp, ..
+    } = divisor_sum(ops, h + 1)?
+    else {
+        return None;
+    };
+
+    let (reset, start) = assign(&body[0])?;
+    let start = constant(start)?;
+    (reset == b && increment(&body[10], 1)? == a).then_some(())?;
+
+    let (gt_dest, gt) = assign(&body[11])?;
+    let exit_cond = Expr::Bin(BinOp::Le, Box::new(Expr::Reg(a)), Box::new(Expr::Reg(n)));
+    (bin(gt, BinOp::Gt)? == (&Expr::Reg(a), &Expr::Reg(n))).then_some(())?;
+    (body[12] == Op::Branch(exit_cond, h) && body[13] == Op::Nop).then_some(())?;
+
+    (gt_dest == tmp).then_some(Idiom::DivisorSumOverRange {
+        a,
+        b,
+        n,
+        acc,
+        tmp,
+        start,
+        exit: h + 14,
+    })
+}
+
+fn count_until_product_exceeds(ops: &[Op], h: usize) -> Option<Idiom> {
+    let body = ops.get(h..h + 8)?;
+    let counter = increment(&body[6], 1)?;
+
+    let (tmp, sum) = assign(&body[0])?;
+    let (l, offset) = bin(sum, BinOp::Add)?;
+    (reg(l) == Some(counter)).then_some(())?;
+    let offset = constant(offset)?;
+
+    let (mul_dest, product) = assign(&body[1])?;
+    let factor = constant(&commuted(product, BinOp::Mul, &Expr::Reg(tmp))?)?;
+
+    let (gt_dest, gt) = assign(&body[2])?;
+    let (l, n) = bin(gt, BinOp::Gt)?;
+    (reg(l) == Some(tmp)).then_some(())?;
+    let n = reg(n)?;
+
+    branch_if_zero(&body[3], tmp, h + 6)?;
+    let Op::Goto(exit) = body[5] else {
+        return None;
+    };
+    (body[4] == Op::Nop && body[7] == Op::Goto(h)).then_some(())?;
+
+    (mul_dest == tmp && gt_dest == tmp && factor > 0 && distinct(&[counter, n, tmp])).then_some(
+        Idiom::CountUntilProductExceeds {
+            counter,
+            offset,
+            factor,
+            n,
+            tmp,
+            exit,
+        },
+    )
+}
+
+// Finds loops matching a known idiom that can only be entered through their first instruction,
+// keyed by that instruction's index
+pub fn find_idioms(program: &[Instr], ip_reg: usize) -> Vec<(usize, Idiom)> {
+    let ops = lift(program, ip_reg);
+    let decompiler = Decompiler::new(&ops);
+
+    let mut result = Vec::new();
+    for h in 0..ops.len() {
+        let found = divisor_sum_over_range(&ops, h)
+            .map(|idiom| (idiom, 14))
+            .or_else(|| divisor_sum(&ops, h).map(|idiom| (idiom, 9)))
+            .or_else(|| count_until_product_exceeds(&ops, h).map(|idiom| (idiom, 8)));
+        if let Some((idiom, len)) = found {
+            if decompiler.is_closed(h, h + len, false) {
+                result.push((h, idiom));
+            }
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::elfcode::{read_program_file, Vm, REGISTERS_CNT};
+
+    // Runs the day 19 loops from `ip` with and without the idioms, they must agree on the cycle
+    fn check_from(ip: i64, start: [i64; REGISTERS_CNT]) {
+        let (program, ip_reg) = read_program_file("src/19.input");
+        let run = |accelerate: bool| {
+            let mut vm = Vm::new(&program, ip_reg, &start);
+            vm.ip = ip;
+            if accelerate {
+                vm.accelerate();
+            }
+            vm.run(None);
+            (vm.registers, vm.ip, vm.cycles)
+        };
+
+        assert_eq!(run(true), run(false), "from ip {ip} with {start:?}");
+    }
+
+    #[test]
+    fn divisor_sums_match_the_loops() {
+        check_from(0, [0; REGISTERS_CNT]);
+        // r5 is the divisor, r4 the number, r2 the cofactor and r0 the sum
+        for n in [-3, 0, 1, 2, 12, 97, 360] {
+            for a in [-2, 0, 1, 3, 400] {
+                check_from(2, [5, 0, 7, 0, n, a]);
+            }
+            check_from(1, [0, 0, 0, 0, n, 0]);
+            check_from(3, [0, 0, 5, 0, n, 2]);
+        }
+    }
+}
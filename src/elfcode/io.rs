@@ -0,0 +1,60 @@
+use std::io::stdin;
+
+use super::REGISTERS_CNT;
+
+// Registers past the regular ones that act as ports once a device is attached to a VM: reading
+// IN_PORT consumes the next input value (-1 when there is none left), writing OUT_PORT prints
+// the value as a number on its own line and writing CHAR_PORT prints it as a character
+pub const IN_PORT: usize = REGISTERS_CNT;
+pub const OUT_PORT: usize = REGISTERS_CNT + 1;
+pub const CHAR_PORT: usize = REGISTERS_CNT + 2;
+pub const PORTS_CNT: usize = 3;
+
+pub const END_OF_INPUT: i64 = -1;
+
+pub struct Device {
+    input: Box<dyn Iterator<Item = i64>>,
+    pub output: String,
+    // Also print the output as it is produced
+    echo: bool,
+}
+
+impl Device {
+    pub fn from_buffer(input: Vec<i64>) -> Self {
+        Device {
+            input: Box::new(input.into_iter()),
+            output: String::new(),
+            echo: false,
+        }
+    }
+
+    // Whitespace separated integers, read a line at a time when the program needs them, with
+    // the output echoed to stdout
+    pub fn from_stdin() -> Self {
+        let input = stdin().lines().map_while(Result::ok).flat_map(|line| {
+            line.split_whitespace()
+                .map(|v| v.parse::<i64>().expect("input should be integers"))
+                .collect::<Vec<_>>()
+        });
+        Device {
+            input: Box::new(input),
+            output: String::new(),
+            echo: true,
+        }
+    }
+
+    pub(crate) fn read(&mut self) -> i64 {
+        self.input.next().unwrap_or(END_OF_INPUT)
+    }
+
+    pub(crate) fn write(&mut self, port: usize, value: i64) {
+        let text = match port {
+            OUT_PORT => format!("{value}\n"),
+            _ => char::from_u32(value as u32).unwrap_or('?').to_string(),
+        };
+        if self.echo {
+            print!("{text}");
+        }
+        self.output.push_str(&text);
+    }
+}
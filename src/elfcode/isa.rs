@@ -0,0 +1,116 @@
+use std::sync::RwLock;
+
+use super::{parse_builtin_opcode, OpCode};
+
+// How an operation uses its first two operands
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Register,
+    Immediate,
+    Ignored,
+}
+
+// An opcode on top of the 16 built-in ones; like those, it computes a value from its first two
+// operands and stores it in the register given by the third, so writing the ip register jumps
+pub trait Operation: Sync {
+    fn mnemonic(&self) -> &'static str;
+    fn operands(&self) -> [Operand; 2];
+    // None if the result is undefined for these values, e.g. on a division by zero
+    fn eval(&self, a: i64, b: i64) -> Option<i64>;
+    // A Rust expression computing the result from the expressions `a` and `b`, for the transpiler
+    fn rust(&self, a: &str, b: &str) -> String;
+}
+
+// An operation given by plain functions, enough for most extensions
+pub struct SimpleOperation {
+    pub mnemonic: &'static str,
+    pub operands: [Operand; 2],
+    pub eval: fn(i64, i64) -> Option<i64>,
+    pub rust: fn(&str, &str) -> String,
+}
+
+impl Operation for SimpleOperation {
+    fn mnemonic(&self) -> &'static str {
+        self.mnemonic
+    }
+
+    fn operands(&self) -> [Operand; 2] {
+        self.operands
+    }
+
+    fn eval(&self, a: i64, b: i64) -> Option<i64> {
+        (self.eval)(a, b)
+    }
+
+    fn rust(&self, a: &str, b: &str) -> String {
+        (self.rust)(a, b)
+    }
+}
+
+// Registered operations live for the rest of the program, `OpCode::Custom` indexes this
+static REGISTRY: RwLock<Vec<&'static dyn Operation>> = RwLock::new(Vec::new());
+
+// Makes the parser, the VMs, the disassembler and `OpCode::all` know about `op`. Registering a
+// mnemonic again keeps the first operation; built-in mnemonics can't be redefined.
+pub fn register(op: impl Operation + 'static) -> OpCode {
+    let mnemonic = op.mnemonic();
+    assert!(
+        parse_builtin_opcode(mnemonic).is_none(),
+        "{mnemonic} is a built-in opcode"
+    );
+
+    let mut registry = REGISTRY.write().unwrap();
+    if let Some(i) = registry.iter().position(|o| o.mnemonic() == mnemonic) {
+        return OpCode::Custom(i);
+    }
+    registry.push(Box::leak(Box::new(op)));
+
+    OpCode::Custom(registry.len() - 1)
+}
+
+pub(crate) fn get(index: usize) -> &'static dyn Operation {
+    REGISTRY.read().unwrap()[index]
+}
+
+pub(crate) fn find(mnemonic: &str) -> Option<OpCode> {
+    let registry = REGISTRY.read().unwrap();
+    let index = registry.iter().position(|o| o.mnemonic() == mnemonic)?;
+
+    Some(OpCode::Custom(index))
+}
+
+pub(crate) fn custom_opcodes() -> Vec<OpCode> {
+    (0..REGISTRY.read().unwrap().len())
+        .map(OpCode::Custom)
+        .collect()
+}
+
+// `divr`, `divi`, `modr` and `modi`, rounding towards zero like Rust does
+pub fn register_division() {
+    use Operand::*;
+
+    register(SimpleOperation {
+        mnemonic: "divr",
+        operands: [Register, Register],
+        eval: i64::checked_div,
+        rust: |a, b| format!("{a} / {b}"),
+    });
+    register(SimpleOperation {
+        mnemonic: "divi",
+        operands: [Register, Immediate],
+        eval: i64::checked_div,
+        rust: |a, b| format!("{a} / {b}"),
+    });
+    register(SimpleOperation {
+        mnemonic: "modr",
+        operands: [Register, Register],
+        eval: i64::checked_rem,
+        rust: |a, b| format!("{a} % {b}"),
+    });
+    register(SimpleOperation {
+        mnemonic: "modi",
+        operands: [Register, Immediate],
+        eval: i64::checked_rem,
+        rust: |a, b| format!("{a} % {b}"),
+    });
+}
@@ -0,0 +1,588 @@
+use std::{fmt, fs, io::stdin};
+
+use idioms::{find_idioms, Idiom};
+use io::{Device, CHAR_PORT, IN_PORT, OUT_PORT, PORTS_CNT};
+use isa::Operand;
+use scanf::sscanf;
+use OpCode::*;
+
+pub mod compile;
+pub mod debugger;
+pub mod decompile;
+pub mod equivalence;
+pub mod idioms;
+pub mod io;
+pub mod isa;
+pub mod profile;
+pub mod rng;
+pub mod transpile;
+
+#[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub enum OpCode {
+    Addr,
+    Addi,
+    Mulr,
+    Muli,
+    Banr,
+    Bani,
+    Borr,
+    Bori,
+    Setr,
+    Seti,
+    Gtir,
+    Gtri,
+    Gtrr,
+    Eqir,
+    Eqri,
+    Eqrr,
+    // Registered through `isa::register`
+    Custom(usize),
+}
+
+const BUILTIN_OPCODES: [OpCode; 16] = [
+    Addr, Addi, Mulr, Muli, Banr, Bani, Borr, Bori, Setr, Seti, Gtir, Gtri, Gtrr, Eqir, Eqri, Eqrr,
+];
+
+pub fn parse_opcode(s: &str) -> OpCode {
+    parse_builtin_opcode(s)
+        .or_else(|| isa::find(s))
+        .unwrap_or_else(|| panic!("unknown opcode {s}"))
+}
+
+fn parse_builtin_opcode(s: &str) -> Option<OpCode> {
+    let code = match s {
+        "addr" => Addr,
+        "addi" => Addi,
+        "mulr" => Mulr,
+        "muli" => Muli,
+        "banr" => Banr,
+        "bani" => Bani,
+        "borr" => Borr,
+        "bori" => Bori,
+        "setr" => Setr,
+        "seti" => Seti,
+        "gtir" => Gtir,
+        "gtri" => Gtri,
+        "gtrr" => Gtrr,
+        "eqir" => Eqir,
+        "eqri" => Eqri,
+        "eqrr" => Eqrr,
+        _ => return None,
+    };
+
+    Some(code)
+}
+
+impl OpCode {
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Addr => "addr",
+            Addi => "addi",
+            Mulr => "mulr",
+            Muli => "muli",
+            Banr => "banr",
+            Bani => "bani",
+            Borr => "borr",
+            Bori => "bori",
+            Setr => "setr",
+            Seti => "seti",
+            Gtir => "gtir",
+            Gtri => "gtri",
+            Gtrr => "gtrr",
+            Eqir => "eqir",
+            Eqri => "eqri",
+            Eqrr => "eqrr",
+            Custom(i) => isa::get(i).mnemonic(),
+        }
+    }
+
+    // The built-in opcodes followed by the registered ones
+    pub fn all() -> Vec<OpCode> {
+        let mut result = BUILTIN_OPCODES.to_vec();
+        result.extend(isa::custom_opcodes());
+        result
+    }
+
+    pub fn operands(self) -> [Operand; 2] {
+        use Operand::*;
+        match self {
+            Addr | Mulr | Banr | Borr | Gtrr | Eqrr => [Register, Register],
+            Addi | Muli | Bani | Bori | Gtri | Eqri => [Register, Immediate],
+            Setr => [Register, Ignored],
+            Seti => [Immediate, Ignored],
+            Gtir | Eqir => [Immediate, Register],
+            Custom(i) => isa::get(i).operands(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Instr {
+    pub code: OpCode,
+    pub op1: i32,
+    pub op2: i32,
+    pub op3: i32,
+}
+
+impl fmt::Display for Instr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.code.mnemonic(),
+            self.op1,
+            self.op2,
+            self.op3
+        )
+    }
+}
+
+pub fn do_instr(instr: Instr, rs: &mut [i64]) {
+    let Instr {
+        code,
+        op1: a,
+        op2: b,
+        op3: c,
+    } = instr;
+    let (a, b, c) = (a as usize, b as usize, c as usize);
+    match code {
+        Addr => rs[c] = rs[a] + rs[b],
+        Addi => rs[c] = rs[a] + b as i64,
+        Mulr => rs[c] = rs[a] * rs[b],
+        Muli => rs[c] = rs[a] * b as i64,
+        Banr => rs[c] = rs[a] & rs[b],
+        Bani => rs[c] = rs[a] & b as i64,
+        Borr => rs[c] = rs[a] | rs[b],
+        Bori => rs[c] = rs[a] | b as i64,
+        Setr => rs[c] = rs[a],
+        Seti => rs[c] = a as i64,
+        Gtir => rs[c] = (a as i64 > rs[b]) as i64,
+        Gtri => rs[c] = (rs[a] > b as i64) as i64,
+        Gtrr => rs[c] = (rs[a] > rs[b]) as i64,
+        Eqir => rs[c] = (a as i64 == rs[b]) as i64,
+        Eqri => rs[c] = (rs[a] == b as i64) as i64,
+        Eqrr => rs[c] = (rs[a] == rs[b]) as i64,
+        Custom(_) => {
+            rs[c] = eval_custom(instr, rs).unwrap_or_else(|| panic!("{instr}: undefined result"))
+        }
+    }
+}
+
+fn eval_custom(instr: Instr, rs: &[i64]) -> Option<i64> {
+    let Custom(i) = instr.code else {
+        unreachable!()
+    };
+    let value = |kind, v: i32| match kind {
+        Operand::Register => rs[v as usize],
+        Operand::Immediate => v as i64,
+        Operand::Ignored => 0,
+    };
+    let [a, b] = instr.code.operands();
+
+    isa::get(i).eval(value(a, instr.op1), value(b, instr.op2))
+}
+
+// Like `do_instr`, but leaves the registers untouched and returns false where that would panic,
+// overflow or produce an undefined result
+pub fn try_instr(instr: Instr, rs: &mut [i64]) -> bool {
+    do_instr_checked(instr, rs, 0, 0).is_ok()
+}
+
+// Like `do_instr`, but rejects register operands out of range and arithmetic overflow instead
+// of panicking or wrapping
+fn do_instr_checked(instr: Instr, rs: &mut [i64], ip: usize, cycle: usize) -> Result<(), VmError> {
+    let mut operands = instr.raw_reads();
+    operands.push(instr.op3);
+    if let Some(&reg) = operands.iter().find(|&&r| r < 0 || r as usize >= rs.len()) {
+        return Err(VmError::BadRegister { reg, ip, cycle });
+    }
+
+    let (a, b, c) = (instr.op1 as usize, instr.op2 as usize, instr.op3 as usize);
+    let overflow = VmError::Overflow { ip, cycle };
+    rs[c] = match instr.code {
+        Addr => rs[a].checked_add(rs[b]).ok_or(overflow)?,
+        Addi => rs[a].checked_add(instr.op2 as i64).ok_or(overflow)?,
+        Mulr => rs[a].checked_mul(rs[b]).ok_or(overflow)?,
+        Muli => rs[a].checked_mul(instr.op2 as i64).ok_or(overflow)?,
+        Custom(_) => eval_custom(instr, rs).ok_or(VmError::Undefined { ip, cycle })?,
+        _ => {
+            do_instr(instr, rs);
+            return Ok(());
+        }
+    };
+
+    Ok(())
+}
+
+impl Instr {
+    fn raw_reads(&self) -> Vec<i32> {
+        let [a, b] = self.code.operands();
+        [(a, self.op1), (b, self.op2)]
+            .into_iter()
+            .filter(|&(kind, _)| kind == Operand::Register)
+            .map(|(_, r)| r)
+            .collect()
+    }
+
+    // Registers the instruction reads, the ip register included if it is an operand
+    pub fn reads(&self) -> Vec<usize> {
+        self.raw_reads().into_iter().map(|r| r as usize).collect()
+    }
+
+    pub fn writes(&self) -> usize {
+        self.op3 as usize
+    }
+}
+
+pub const REGISTERS_CNT: usize = 6;
+
+// Called with the ip, the instruction and the registers before it executes
+pub type WatchPredicate = Box<dyn Fn(usize, Instr, &[i64]) -> bool>;
+
+pub enum Watch {
+    Opcode(OpCode),
+    Ip(usize),
+    RegRead(usize),
+    RegWrite(usize),
+    Predicate(WatchPredicate),
+}
+
+impl Watch {
+    fn matches(&self, ip: usize, instr: Instr, registers: &[i64]) -> bool {
+        match self {
+            Watch::Opcode(code) => instr.code == *code,
+            Watch::Ip(watched) => ip == *watched,
+            Watch::RegRead(r) => instr.reads().contains(r),
+            Watch::RegWrite(r) => instr.writes() == *r,
+            Watch::Predicate(f) => f(ip, instr, registers),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Event {
+    // Index of the watch that fired, as returned by `Vm::watch`
+    pub watch: usize,
+    pub cycle: usize,
+    pub ip: usize,
+    pub instr: Instr,
+    // Register values right before the instruction executed
+    pub registers: Vec<i64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VmError {
+    BadRegister { reg: i32, ip: usize, cycle: usize },
+    Overflow { ip: usize, cycle: usize },
+    Undefined { ip: usize, cycle: usize },
+    NegativeIp { ip: i64, cycle: usize },
+    CycleBudgetExhausted { ip: i64, cycle: usize },
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::BadRegister { reg, ip, cycle } => {
+                write!(f, "bad register {reg} at ip {ip}, cycle {cycle}")
+            }
+            VmError::Overflow { ip, cycle } => {
+                write!(f, "arithmetic overflow at ip {ip}, cycle {cycle}")
+            }
+            VmError::Undefined { ip, cycle } => {
+                write!(f, "undefined result at ip {ip}, cycle {cycle}")
+            }
+            VmError::NegativeIp { ip, cycle } => write!(f, "negative ip {ip} at cycle {cycle}"),
+            VmError::CycleBudgetExhausted { ip, cycle } => {
+                write!(f, "cycle budget exhausted at ip {ip}, cycle {cycle}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for VmError {}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub registers: Vec<i64>,
+    pub ip: i64,
+    pub cycles: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    Halted,
+    // The state at `entry_cycle` comes back every `period` cycles; the loop may have started
+    // before it
+    NeverHalts { entry_cycle: usize, period: usize },
+}
+
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Termination::Halted => write!(f, "program halts"),
+            Termination::NeverHalts {
+                entry_cycle,
+                period,
+            } => write!(
+                f,
+                "program never halts: it is looping by cycle {entry_cycle} with a period of {period} cycles"
+            ),
+        }
+    }
+}
+
+pub struct Vm<'a> {
+    program: &'a [Instr],
+    ip_reg: usize,
+    pub registers: Vec<i64>,
+    pub ip: i64,
+    pub cycles: usize,
+    watches: Vec<Watch>,
+    pub events: Vec<Event>,
+    // Indexed by ip, empty unless `accelerate` was called
+    idioms: Vec<Option<Idiom>>,
+    pub io: Option<Device>,
+}
+
+impl<'a> Vm<'a> {
+    pub fn new(program: &'a [Instr], ip_reg: usize, start_state: &[i64]) -> Self {
+        Vm {
+            program,
+            ip_reg,
+            registers: start_state.to_vec(),
+            ip: 0,
+            cycles: 0,
+            watches: Vec::new(),
+            events: Vec::new(),
+            idioms: Vec::new(),
+            io: None,
+        }
+    }
+
+    // Adds the port registers, see `io`; restoring a snapshot doesn't take back input or output
+    pub fn attach(&mut self, device: Device) {
+        let len = self.registers.len().max(REGISTERS_CNT + PORTS_CNT);
+        self.registers.resize(len, 0);
+        self.io = Some(device);
+    }
+
+    // Replaces loops recognized by `find_idioms` with a native computation of their result;
+    // watches don't see the instructions inside such loops
+    pub fn accelerate(&mut self) {
+        self.idioms = vec![None; self.program.len()];
+        for (ip, idiom) in find_idioms(self.program, self.ip_reg) {
+            self.idioms[ip] = Some(idiom);
+        }
+    }
+
+    pub fn watch(&mut self, watch: Watch) -> usize {
+        self.watches.push(watch);
+        self.watches.len() - 1
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            registers: self.registers.clone(),
+            ip: self.ip,
+            cycles: self.cycles,
+        }
+    }
+
+    // Also forgets the events recorded after the snapshot was taken
+    pub fn restore(&mut self, snapshot: &Snapshot) {
+        self.registers.clone_from(&snapshot.registers);
+        self.ip = snapshot.ip;
+        self.cycles = snapshot.cycles;
+        self.events.retain(|e| e.cycle < snapshot.cycles);
+    }
+
+    pub fn halted(&self) -> bool {
+        self.ip < 0 || self.ip as usize >= self.program.len()
+    }
+
+    // Executes one instruction, returns false if the program has already halted
+    pub fn step(&mut self) -> bool {
+        if self.halted() {
+            return false;
+        }
+        let ip = self.ip as usize;
+        if let Some(Some(idiom)) = self.idioms.get(ip) {
+            let (next_ip, cycles) = idiom.apply(&mut self.registers);
+            self.registers[self.ip_reg] = next_ip - 1;
+            self.ip = next_ip;
+            self.cycles += cycles;
+            return true;
+        }
+
+        let instr = self.program[ip];
+        self.registers[self.ip_reg] = self.ip;
+        self.read_port(instr);
+        self.record_events(ip, instr);
+
+        do_instr(instr, &mut self.registers);
+        self.write_port(instr);
+        self.ip = self.registers[self.ip_reg] + 1;
+        self.cycles += 1;
+
+        true
+    }
+
+    fn read_port(&mut self, instr: Instr) {
+        if let Some(io) = &mut self.io {
+            if instr.reads().contains(&IN_PORT) {
+                self.registers[IN_PORT] = io.read();
+            }
+        }
+    }
+
+    fn write_port(&mut self, instr: Instr) {
+        if let Some(io) = &mut self.io {
+            let port = instr.writes();
+            if port == OUT_PORT || port == CHAR_PORT {
+                io.write(port, self.registers[port]);
+            }
+        }
+    }
+
+    fn record_events(&mut self, ip: usize, instr: Instr) {
+        for (i, watch) in self.watches.iter().enumerate() {
+            if watch.matches(ip, instr, &self.registers) {
+                self.events.push(Event {
+                    watch: i,
+                    cycle: self.cycles,
+                    ip,
+                    instr,
+                    registers: self.registers.clone(),
+                });
+            }
+        }
+    }
+
+    // Checked counterpart of `step`, it never runs loops natively even after `accelerate`
+    pub fn step_checked(&mut self) -> Result<bool, VmError> {
+        let cycle = self.cycles;
+        if self.ip < 0 {
+            return Err(VmError::NegativeIp { ip: self.ip, cycle });
+        }
+        if self.halted() {
+            return Ok(false);
+        }
+        let ip = self.ip as usize;
+        if self.ip_reg >= self.registers.len() {
+            let reg = self.ip_reg as i32;
+            return Err(VmError::BadRegister { reg, ip, cycle });
+        }
+
+        let instr = self.program[ip];
+        self.registers[self.ip_reg] = self.ip;
+        self.read_port(instr);
+        self.record_events(ip, instr);
+
+        do_instr_checked(instr, &mut self.registers, ip, cycle)?;
+        self.write_port(instr);
+        self.ip = self.registers[self.ip_reg]
+            .checked_add(1)
+            .ok_or(VmError::Overflow { ip, cycle })?;
+        self.cycles += 1;
+
+        Ok(true)
+    }
+
+    // Runs until the program halts by moving the ip past its end; not halting within
+    // `cycle_budget` instructions is an error
+    pub fn run_checked(&mut self, cycle_budget: Option<usize>) -> Result<(), VmError> {
+        loop {
+            if !self.halted() && cycle_budget.is_some_and(|budget| self.cycles >= budget) {
+                return Err(VmError::CycleBudgetExhausted {
+                    ip: self.ip,
+                    cycle: self.cycles,
+                });
+            }
+            if !self.step_checked()? {
+                return Ok(());
+            }
+        }
+    }
+
+    // Runs without a limit until the program halts or reaches a state, ip and registers, it was
+    // already in. A repeating state has to come back right after a jump that doesn't move the ip
+    // forward, so only those are remembered. With a device attached, the next input would have to
+    // be part of the state, so nothing is detected and this only returns once the program halts.
+    pub fn run_detecting_loops(&mut self) -> Termination {
+        // Brent's cycle detection over the states after backward jumps: only one checkpoint is
+        // kept, moved to the current state whenever twice as many states went by without it
+        // coming back, so memory stays constant however long the program runs
+        let mut checkpoint: Option<(i64, Vec<i64>, usize)> = None;
+        let (mut since_checkpoint, mut power) = (0usize, 1usize);
+        loop {
+            let ip = self.ip;
+            if !self.step() {
+                return Termination::Halted;
+            }
+            if self.ip > ip || self.io.is_some() {
+                continue;
+            }
+
+            if let Some((cp_ip, cp_registers, entry_cycle)) = &checkpoint {
+                if *cp_ip == self.ip && *cp_registers == self.registers {
+                    return Termination::NeverHalts {
+                        entry_cycle: *entry_cycle,
+                        period: self.cycles - entry_cycle,
+                    };
+                }
+            }
+            since_checkpoint += 1;
+            if checkpoint.is_none() || since_checkpoint == power {
+                checkpoint = Some((self.ip, self.registers.clone(), self.cycles));
+                since_checkpoint = 0;
+                power *= 2;
+            }
+        }
+    }
+
+    pub fn run(&mut self, instructions_limit: Option<usize>) {
+        while instructions_limit.is_none_or(|limit| self.cycles < limit) && self.step() {}
+    }
+}
+
+pub fn execute(
+    program: &[Instr],
+    ip_reg: usize,
+    start_state: &[i64],
+    instructions_limit: Option<usize>,
+) -> Vec<i64> {
+    let mut vm = Vm::new(program, ip_reg, start_state);
+    vm.run(instructions_limit);
+
+    vm.registers
+}
+
+pub fn parse_program(lines: impl Iterator<Item = String>) -> (Vec<Instr>, usize) {
+    let mut ip_register = 0;
+    let mut instructions = Vec::new();
+    for line in lines {
+        let mut ip_reg = 0;
+        let (mut instr_type, mut op1, mut op2, mut op3) = (String::new(), 0, 0, 0);
+        if sscanf!(&line, "#ip {}", ip_reg).is_ok() {
+            ip_register = ip_reg;
+        } else if sscanf!(&line, "{} {} {} {}", instr_type, op1, op2, op3).is_ok() {
+            instructions.push(Instr {
+                code: parse_opcode(&instr_type),
+                op1,
+                op2,
+                op3,
+            });
+        }
+    }
+
+    (instructions, ip_register)
+}
+
+pub fn read_program_file(path: &str) -> (Vec<Instr>, usize) {
+    let contents = fs::read_to_string(path).expect("should be able to read program file");
+    parse_program(contents.lines().map(|l| l.to_string()))
+}
+
+pub fn read_program() -> (Vec<Instr>, usize) {
+    parse_program(stdin().lines().map(|l| l.unwrap()))
+}
@@ -0,0 +1,117 @@
+use std::collections::HashMap;
+
+use super::{Instr, OpCode, Vm};
+
+// A loop is identified by a taken backward jump, `end` being the jumping instruction
+#[derive(Debug, Clone, Copy)]
+pub struct HotLoop {
+    pub start: usize,
+    pub end: usize,
+    pub iterations: usize,
+    // Instructions executed inside start..=end, nested loops included
+    pub cycles: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Profile {
+    pub per_ip: Vec<usize>,
+    pub per_opcode: HashMap<OpCode, usize>,
+    pub loops: Vec<HotLoop>,
+    pub total: usize,
+}
+
+pub fn profile(
+    program: &[Instr],
+    ip_reg: usize,
+    start_state: &[i64],
+    instructions_limit: Option<usize>,
+) -> Profile {
+    let mut vm = Vm::new(program, ip_reg, start_state);
+    let mut per_ip = vec![0; program.len()];
+    let mut per_opcode = HashMap::new();
+    let mut back_jumps: HashMap<(usize, usize), usize> = HashMap::new();
+
+    while instructions_limit.is_none_or(|limit| vm.cycles < limit) {
+        let ip = vm.ip as usize;
+        if !vm.step() {
+            break;
+        }
+        per_ip[ip] += 1;
+        *per_opcode.entry(program[ip].code).or_insert(0) += 1;
+
+        if vm.ip <= ip as i64 && vm.ip >= 0 {
+            *back_jumps.entry((vm.ip as usize, ip)).or_insert(0) += 1;
+        }
+    }
+
+    let mut loops = back_jumps
+        .into_iter()
+        .map(|((start, end), iterations)| HotLoop {
+            start,
+            end,
+            iterations,
+            cycles: per_ip[start..=end].iter().sum(),
+        })
+        .collect::<Vec<_>>();
+    loops.sort_by_key(|l| (usize::MAX - l.cycles, l.start));
+
+    Profile {
+        per_ip,
+        per_opcode,
+        loops,
+        total: vm.cycles,
+    }
+}
+
+fn percent(count: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        count as f64 * 100.0 / total as f64
+    }
+}
+
+const BAR_WIDTH: usize = 30;
+
+// The program with each instruction's execution count, share of all executed instructions
+// and a bar, followed by the opcode counts and the hottest loops
+pub fn annotated_listing(program: &[Instr], ip_reg: usize, profile: &Profile) -> String {
+    let mut result = format!("#ip {ip_reg}\n");
+    for (ip, instr) in program.iter().enumerate() {
+        let count = profile.per_ip[ip];
+        let share = percent(count, profile.total);
+        let bar = "#".repeat((share * BAR_WIDTH as f64 / 100.0).round() as usize);
+        let line = format!(
+            "{ip:>4}  {:<20}{count:>14} {share:>6.2}%  {bar}",
+            instr.to_string()
+        );
+        result.push_str(line.trim_end());
+        result.push('\n');
+    }
+
+    result.push_str("\nopcodes:\n");
+    let mut opcodes = profile.per_opcode.iter().collect::<Vec<_>>();
+    opcodes.sort_by_key(|(code, &count)| (usize::MAX - count, code.mnemonic()));
+    for (code, &count) in opcodes {
+        result.push_str(&format!(
+            "    {}{count:>14} {:>6.2}%\n",
+            code.mnemonic(),
+            percent(count, profile.total)
+        ));
+    }
+
+    result.push_str("\nhot loops:\n");
+    for l in profile.loops.iter().take(10) {
+        result.push_str(&format!(
+            "    {:>4}..={:<4} {:>12} iterations {:>14} cycles {:>6.2}%\n",
+            l.start,
+            l.end,
+            l.iterations,
+            l.cycles,
+            percent(l.cycles, profile.total)
+        ));
+    }
+    result.push_str(&format!("\ntotal: {} instructions\n", profile.total));
+
+    result
+}
@@ -0,0 +1,172 @@
+use std::{
+    env, fs,
+    io::{self, Error},
+    path::Path,
+    process::{self, Command, Output},
+};
+
+use super::{
+    isa::{self, Operand},
+    Instr,
+    OpCode::*,
+    REGISTERS_CNT,
+};
+
+fn register(r: i32, ip: usize, ip_reg: usize) -> String {
+    if r as usize == ip_reg {
+        ip.to_string()
+    } else {
+        format!("r{r}")
+    }
+}
+
+fn immediate(v: i32) -> String {
+    if v < 0 {
+        format!("({v})")
+    } else {
+        v.to_string()
+    }
+}
+
+// The value an instruction computes, reads of the ip register being the constant `ip`
+fn expression(instr: &Instr, ip: usize, ip_reg: usize) -> String {
+    let (a, b) = (instr.op1, instr.op2);
+    let r = |x| register(x, ip, ip_reg);
+    match instr.code {
+        Addr => format!("{} + {}", r(a), r(b)),
+        Addi => format!("{} + {}", r(a), immediate(b)),
+        Mulr => format!("{} * {}", r(a), r(b)),
+        Muli => format!("{} * {}", r(a), immediate(b)),
+        Banr => format!("{} & {}", r(a), r(b)),
+        Bani => format!("{} & {}", r(a), immediate(b)),
+        Borr => format!("{} | {}", r(a), r(b)),
+        Bori => format!("{} | {}", r(a), immediate(b)),
+        Setr => r(a),
+        Seti => immediate(a),
+        Gtir => format!("({} > {}) as i64", immediate(a), r(b)),
+        Gtri => format!("({} > {}) as i64", r(a), immediate(b)),
+        Gtrr => format!("({} > {}) as i64", r(a), r(b)),
+        Eqir => format!("({} == {}) as i64", immediate(a), r(b)),
+        Eqri => format!("({} == {}) as i64", r(a), immediate(b)),
+        Eqrr => format!("({} == {}) as i64", r(a), r(b)),
+        Custom(i) => {
+            let value = |kind, v| match kind {
+                Operand::Register => r(v),
+                Operand::Immediate => immediate(v),
+                Operand::Ignored => "0".to_string(),
+            };
+            let [kind_a, kind_b] = instr.code.operands();
+            format!(
+                "({})",
+                isa::get(i).rust(&value(kind_a, a), &value(kind_b, b))
+            )
+        }
+    }
+}
+
+// A standalone Rust program: `run` executes the ElfCode program with a `match ip` dispatch loop
+// over the registers kept in locals, returning the number of executed instructions, and `main`
+// takes the initial registers as arguments and prints the final ones followed by the cycle count
+pub fn transpile(program: &[Instr], ip_reg: usize) -> String {
+    let regs = (0..REGISTERS_CNT)
+        .map(|r| format!("r{r}"))
+        .collect::<Vec<_>>();
+    let mut arms = String::new();
+    for (ip, instr) in program.iter().enumerate() {
+        let value = expression(instr, ip, ip_reg);
+        let arm = if instr.writes() == ip_reg {
+            format!("{value} + 1")
+        } else {
+            format!("{{ r{} = {value}; {} }}", instr.op3, ip + 1)
+        };
+        arms.push_str(&format!(
+            "            // {instr}\n            {ip} => {arm},\n"
+        ));
+    }
+
+    format!(
+        "// Transpiled from ElfCode, #ip {ip_reg}
+#[allow(unused_mut, unused_variables, unused_assignments, unused_parens)]
+pub fn run(registers: &mut [i64; {REGISTERS_CNT}], instructions_limit: Option<usize>) -> usize {{
+    let [{mut_regs}] = *registers;
+    let limit = instructions_limit.unwrap_or(usize::MAX);
+    let mut ip: i64 = 0;
+    let mut cycles = 0;
+    while cycles < limit {{
+        ip = match ip {{
+{arms}            _ => break,
+        }};
+        cycles += 1;
+    }}
+    if cycles > 0 {{
+        r{ip_reg} = ip - 1;
+    }}
+    *registers = [{regs}];
+
+    cycles
+}}
+
+fn main() {{
+    let mut registers = [0; {REGISTERS_CNT}];
+    for (r, arg) in registers.iter_mut().zip(std::env::args().skip(1)) {{
+        *r = arg.parse().expect(\"registers should be integers\");
+    }}
+    let cycles = run(&mut registers, None);
+    println!(\"{{registers:?}} {{cycles}}\");
+}}
+",
+        mut_regs = regs
+            .iter()
+            .map(|r| format!("mut {r}"))
+            .collect::<Vec<_>>()
+            .join(", "),
+        regs = regs.join(", "),
+    )
+}
+
+// Compiles the output of `transpile` with an optimizing rustc and runs it from `start_state`,
+// returning the final registers and the number of executed instructions
+pub fn build_and_run(source: &str, start_state: &[i64]) -> io::Result<(Vec<i64>, usize)> {
+    let dir = env::temp_dir().join(format!("elfcode-{}", process::id()));
+    let output = build_and_run_in(&dir, source, start_state);
+    // The directory goes away whatever happened, a failure to remove it only matters if all
+    // went well otherwise
+    let removed = fs::remove_dir_all(&dir);
+    let output = output?;
+    removed?;
+
+    // `[1, 2, 3] 42`
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (registers, cycles) = stdout
+        .trim()
+        .trim_start_matches('[')
+        .split_once("] ")
+        .ok_or_else(|| Error::other(format!("unexpected output: {stdout}")))?;
+    let parse_error = |e| Error::other(format!("unexpected output {stdout}: {e}"));
+    let registers = registers
+        .split(", ")
+        .map(|v| v.parse::<i64>().map_err(parse_error))
+        .collect::<io::Result<Vec<_>>>()?;
+    let cycles = cycles.parse::<usize>().map_err(parse_error)?;
+
+    Ok((registers, cycles))
+}
+
+fn build_and_run_in(dir: &Path, source: &str, start_state: &[i64]) -> io::Result<Output> {
+    fs::create_dir_all(dir)?;
+    let (src, bin) = (dir.join("main.rs"), dir.join("main"));
+    fs::write(&src, source)?;
+
+    let status = Command::new("rustc")
+        .args(["-O", "--edition", "2021", "-o"])
+        .arg(&bin)
+        .arg(&src)
+        .status()?;
+    if !status.success() {
+        return Err(Error::other(format!("rustc failed: {status}")));
+    }
+
+    Command::new(&bin)
+        .args(start_state.iter().map(|v| v.to_string()))
+        .output()
+}